@@ -0,0 +1,105 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use toy_transactions_engine::engine::{process_transactions, Engine, EngineConfig};
+use toy_transactions_engine::generator;
+
+const SEED: u64 = 42;
+const ROWS: usize = 1_000_000;
+/// Clients the generator spreads transactions over.
+const CLIENTS: usize = 100;
+
+fn bench_process_transactions(c: &mut Criterion) {
+    let transactions = generator::transactions(SEED, ROWS);
+    let mut group = c.benchmark_group("process_transactions");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.bench_function("1M mixed", |b| {
+        b.iter_batched(
+            || transactions.clone(),
+            process_transactions,
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_engine_apply(c: &mut Criterion) {
+    let transactions = generator::transactions(SEED, ROWS);
+    let mut group = c.benchmark_group("engine_apply");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(ROWS as u64));
+    group.bench_function("1M mixed", |b| {
+        b.iter_batched(
+            || transactions.clone(),
+            |transactions| {
+                let mut engine = Engine::new(EngineConfig::default());
+                for transaction in transactions {
+                    let _ = engine.apply(transaction);
+                }
+                engine.finish()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("1M mixed pre-sized", |b| {
+        b.iter_batched(
+            || transactions.clone(),
+            |transactions| {
+                let mut engine =
+                    Engine::with_capacity(CLIENTS, transactions.len(), EngineConfig::default());
+                for transaction in transactions {
+                    let _ = engine.apply(transaction);
+                }
+                engine.finish()
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Inserts and looks up every transaction id, the engine's hottest map access.
+#[cfg(feature = "fxhash")]
+fn bench_hashers(c: &mut Criterion) {
+    use criterion::black_box;
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+
+    const TXS: u32 = 5_000_000;
+    let mut group = c.benchmark_group("tx_map");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(u64::from(TXS)));
+    group.bench_function("5M std HashMap", |b| {
+        b.iter(|| {
+            let mut map = HashMap::new();
+            for tx in 0..TXS {
+                map.insert(tx, Decimal::from(tx));
+            }
+            (0..TXS)
+                .filter(|tx| map.contains_key(black_box(tx)))
+                .count()
+        })
+    });
+    group.bench_function("5M FxHashMap", |b| {
+        b.iter(|| {
+            let mut map = rustc_hash::FxHashMap::default();
+            for tx in 0..TXS {
+                map.insert(tx, Decimal::from(tx));
+            }
+            (0..TXS)
+                .filter(|tx| map.contains_key(black_box(tx)))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+#[cfg(not(feature = "fxhash"))]
+fn bench_hashers(_: &mut Criterion) {}
+
+criterion_group!(
+    benches,
+    bench_process_transactions,
+    bench_engine_apply,
+    bench_hashers
+);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rust_decimal::Decimal;
+use toy_transactions_engine::engine::{Engine, EngineConfig};
+use toy_transactions_engine::types::{DisputePolicy, Transaction};
+
+const TYPES: [&str; 7] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "chargeback_reversal",
+    "transfer",
+];
+
+/// A transaction over few clients and ids, so disputes and resolves hit
+/// earlier rows often.
+#[derive(Debug, Arbitrary)]
+struct FuzzTransaction {
+    kind: u8,
+    client: u8,
+    tx: u8,
+    amount: Option<(i64, u8)>,
+    destination: Option<u8>,
+}
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    allow_negative: bool,
+    allow_redispute: bool,
+    partial_chargebacks: bool,
+    transactions: Vec<FuzzTransaction>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut engine = Engine::new(EngineConfig {
+        dispute_policy: if input.allow_negative {
+            DisputePolicy::AllowNegative
+        } else {
+            DisputePolicy::StrictAvailable
+        },
+        allow_redispute: input.allow_redispute,
+        partial_chargebacks: input.partial_chargebacks,
+        ..EngineConfig::default()
+    });
+    for fuzzed in input.transactions {
+        let amount = fuzzed
+            .amount
+            .map(|(mantissa, scale)| Decimal::new(mantissa, u32::from(scale % 5)));
+        let mut transaction = Transaction::new(
+            TYPES[usize::from(fuzzed.kind) % TYPES.len()].to_string(),
+            u16::from(fuzzed.client % 8),
+            u32::from(fuzzed.tx % 32),
+            amount,
+        );
+        transaction.destination = fuzzed.destination.map(|client| u16::from(client % 8));
+        let client = transaction.client;
+        let _ = engine.apply(transaction);
+        if let Some(account) = engine.account(client) {
+            assert!(account.is_consistent(), "inconsistent balances: {:?}", account);
+        }
+    }
+    let (accounts, _) = engine.finish();
+    for account in accounts {
+        assert_eq!(account.total, account.available + account.held);
+        assert!(!account.held.is_sign_negative(), "negative held: {:?}", account);
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use toy_transactions_engine::engine::process_transactions;
+use toy_transactions_engine::helpers::{process_csv_bytes, ParseConfig};
+
+// Arbitrary bytes must parse or fail cleanly, and whatever parses must leave
+// consistent balances.
+fuzz_target!(|data: &[u8]| {
+    if let Ok((transactions, _)) = process_csv_bytes(data, &ParseConfig::default()) {
+        let (accounts, _) = process_transactions(transactions);
+        for account in accounts {
+            assert!(account.is_consistent(), "inconsistent balances: {:?}", account);
+        }
+    }
+});
@@ -0,0 +1,1223 @@
+use crate::types::{Account, DisputePolicy, MAX_AMOUNT_SCALE};
+use crate::{diff, engine, generator, helpers};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// `--progress` prints a line every this many rows, at most once per interval.
+const PROGRESS_ROWS: usize = 100_000;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// `eprintln!` unless `--quiet` is given.
+macro_rules! diagnostic {
+    ($options:expr, $($arg:tt)*) => {
+        if !$options.quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub input: Option<String>,
+    pub output_tx_errs: bool,
+    pub allowlist: Option<String>,
+    pub strict: bool,
+    pub snapshot: Option<String>,
+    pub changed_only: bool,
+    pub allow_redispute: bool,
+    pub allow_locked_resolve: bool,
+    pub validate: bool,
+    pub verbose: bool,
+    pub clients: Vec<u16>,
+    pub history: Option<String>,
+    pub available_precision: Option<u32>,
+    pub held_precision: Option<u32>,
+    pub large_deposit_alert: Option<Decimal>,
+    pub load_state: Option<String>,
+    pub save_state: Option<String>,
+    pub precision: u32,
+    pub omit_zero_accounts: bool,
+    pub order: engine::OutputOrder,
+    pub normalize_balances: bool,
+    pub benchmark: bool,
+    pub limit: Option<usize>,
+    pub totals_row: bool,
+    pub verify_amounts: bool,
+    pub input_format: helpers::InputFormat,
+    pub split_output: Option<String>,
+    pub clamp_negative_dust: bool,
+    pub dispute_policy: DisputePolicy,
+    pub report_json: bool,
+    pub partial_chargebacks: bool,
+    pub output: Option<String>,
+    pub largest_undisputed: Option<usize>,
+    pub aliases: HashMap<String, String>,
+    pub fail_on_locked: bool,
+    pub trace: Option<String>,
+    pub strict_types: bool,
+    pub defer_unresolved: bool,
+    pub max_clients: Option<usize>,
+    pub pad_width: Option<usize>,
+    pub listen: Option<String>,
+    pub progress: bool,
+    pub write_retries: u32,
+    pub overdraft: Decimal,
+    pub group_errors: bool,
+    pub quiet: bool,
+    pub reserve_zero: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            input: None,
+            output_tx_errs: false,
+            allowlist: None,
+            strict: false,
+            snapshot: None,
+            changed_only: false,
+            allow_redispute: false,
+            allow_locked_resolve: false,
+            validate: false,
+            verbose: false,
+            clients: Vec::new(),
+            history: None,
+            available_precision: None,
+            held_precision: None,
+            large_deposit_alert: None,
+            load_state: None,
+            save_state: None,
+            precision: engine::DEFAULT_PRECISION,
+            omit_zero_accounts: false,
+            order: engine::OutputOrder::ByClientId,
+            normalize_balances: false,
+            benchmark: false,
+            limit: None,
+            totals_row: false,
+            verify_amounts: false,
+            input_format: helpers::InputFormat::Csv,
+            split_output: None,
+            clamp_negative_dust: false,
+            dispute_policy: DisputePolicy::StrictAvailable,
+            report_json: false,
+            partial_chargebacks: false,
+            output: None,
+            largest_undisputed: None,
+            aliases: HashMap::new(),
+            fail_on_locked: false,
+            trace: None,
+            strict_types: false,
+            defer_unresolved: false,
+            max_clients: None,
+            pad_width: None,
+            listen: None,
+            progress: false,
+            write_retries: 0,
+            overdraft: Decimal::ZERO,
+            group_errors: false,
+            quiet: false,
+            reserve_zero: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        // The config file only sets defaults, so it's read before any flag.
+        let mut options = match args.iter().position(|arg| arg == "--config") {
+            Some(index) => match args.get(index + 1) {
+                Some(path) => Config::load(path)?.options()?,
+                None => return Err("missing value for \"--config\"".to_string()),
+            },
+            None => Options::default(),
+        };
+        let mut positional: Vec<&String> = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--client" => {
+                    let client = value(&mut iter, arg)?;
+                    let client = client
+                        .parse()
+                        .map_err(|_| format!("invalid client id \"{}\"", client))?;
+                    options.clients.push(client);
+                }
+                "--available-precision" => {
+                    options.available_precision = Some(precision(&mut iter, arg)?)
+                }
+                "--config" => {
+                    value(&mut iter, arg)?;
+                }
+                "--precision" => options.precision = precision(&mut iter, arg)?,
+                "--held-precision" => options.held_precision = Some(precision(&mut iter, arg)?),
+                "--overdraft" => {
+                    let overdraft = value(&mut iter, arg)?;
+                    options.overdraft = match overdraft.parse::<Decimal>() {
+                        Ok(amount) => check_overdraft(amount, arg)?,
+                        _ => {
+                            return Err(format!("invalid amount \"{}\" for \"{}\"", overdraft, arg))
+                        }
+                    };
+                }
+                "--large-deposit-alert" => {
+                    let threshold = value(&mut iter, arg)?;
+                    let threshold = threshold
+                        .parse()
+                        .map_err(|_| format!("invalid amount \"{}\" for \"{}\"", threshold, arg))?;
+                    options.large_deposit_alert = Some(threshold);
+                }
+                "--limit" => {
+                    let limit = value(&mut iter, arg)?;
+                    let limit = limit
+                        .parse()
+                        .map_err(|_| format!("invalid limit \"{}\"", limit))?;
+                    options.limit = Some(limit);
+                }
+                "--alias" => {
+                    let alias = value(&mut iter, arg)?;
+                    let (from, to) = alias
+                        .split_once('=')
+                        .ok_or_else(|| format!("invalid alias \"{}\", expected from=to", alias))?;
+                    options.aliases.insert(from.to_string(), to.to_string());
+                }
+                "--output" => options.output = Some(value(&mut iter, arg)?),
+                "--split-output" => options.split_output = Some(value(&mut iter, arg)?),
+                "--report" => match value(&mut iter, arg)?.as_str() {
+                    "json" => options.report_json = true,
+                    report => return Err(format!("unknown report format \"{}\"", report)),
+                },
+                "--pad-width" => {
+                    let width = value(&mut iter, arg)?;
+                    let width = width
+                        .parse()
+                        .map_err(|_| format!("invalid width \"{}\" for \"{}\"", width, arg))?;
+                    options.pad_width = Some(width);
+                }
+                "--progress" => options.progress = true,
+                "--write-retries" => {
+                    let retries = value(&mut iter, arg)?;
+                    let retries = retries
+                        .parse()
+                        .map_err(|_| format!("invalid count \"{}\" for \"{}\"", retries, arg))?;
+                    options.write_retries = retries;
+                }
+                "--listen" => options.listen = Some(value(&mut iter, arg)?),
+                "--max-clients" => {
+                    let max = value(&mut iter, arg)?;
+                    let max = max
+                        .parse()
+                        .map_err(|_| format!("invalid count \"{}\" for \"{}\"", max, arg))?;
+                    options.max_clients = Some(max);
+                }
+                "--largest-undisputed" => {
+                    let count = value(&mut iter, arg)?;
+                    let count = count
+                        .parse()
+                        .map_err(|_| format!("invalid count \"{}\" for \"{}\"", count, arg))?;
+                    options.largest_undisputed = Some(count);
+                }
+                "--history" => options.history = Some(value(&mut iter, arg)?),
+                "--trace" => options.trace = Some(value(&mut iter, arg)?),
+                "--verbose" => options.verbose = true,
+                "--quiet" => options.quiet = true,
+                "--group-errors" => options.group_errors = true,
+                "--validate" => options.validate = true,
+                "--benchmark-mode" => options.benchmark = true,
+                "--strict" => options.strict = true,
+                "--strict-types" => options.strict_types = true,
+                "--allow-redispute" => options.allow_redispute = true,
+                "--allow-locked-resolve" => options.allow_locked_resolve = true,
+                "--dispute-policy" => {
+                    options.dispute_policy = dispute_policy(&value(&mut iter, arg)?)?
+                }
+                "--partial-chargebacks" => options.partial_chargebacks = true,
+                "--defer-unresolved" => options.defer_unresolved = true,
+                "--verify-amounts" => options.verify_amounts = true,
+                "--order" => {
+                    options.order = match value(&mut iter, arg)?.as_str() {
+                        "client-id" => engine::OutputOrder::ByClientId,
+                        "first-seen" => engine::OutputOrder::FirstSeen,
+                        order => return Err(format!("unknown order \"{}\"", order)),
+                    }
+                }
+                "--input-format" => {
+                    options.input_format = match value(&mut iter, arg)?.as_str() {
+                        "csv" => helpers::InputFormat::Csv,
+                        "ndjson" => helpers::InputFormat::Ndjson,
+                        format => return Err(format!("unknown input format \"{}\"", format)),
+                    }
+                }
+                "--normalize-balances" => options.normalize_balances = true,
+                "--totals-row" => options.totals_row = true,
+                "--fail-on-locked" => options.fail_on_locked = true,
+                "--clamp-negative-dust" => options.clamp_negative_dust = true,
+                "--omit-zero-accounts" | "--hide-zero" => options.omit_zero_accounts = true,
+                "--reserve-zero" => options.reserve_zero = true,
+                "--changed-only" => options.changed_only = true,
+                "--load-state" => options.load_state = Some(value(&mut iter, arg)?),
+                "--save-state" => options.save_state = Some(value(&mut iter, arg)?),
+                "--snapshot" => options.snapshot = Some(value(&mut iter, arg)?),
+                "--allowlist" => options.allowlist = Some(value(&mut iter, arg)?),
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option \"{}\"", flag));
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        options.input = positional.first().map(|input| input.to_string());
+        if let Some(flag) = positional.get(1) {
+            options.output_tx_errs = *flag == "true" || *flag == "1";
+        }
+        // Only the accounts are printed in quiet mode, even with errors on.
+        if options.quiet {
+            options.output_tx_errs = false;
+        }
+
+        Ok(options)
+    }
+}
+
+fn value<'a>(iter: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<String, String> {
+    iter.next()
+        .cloned()
+        .ok_or_else(|| format!("missing value for \"{}\"", flag))
+}
+
+fn precision<'a>(iter: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<u32, String> {
+    let precision = value(iter, flag)?;
+    match precision.parse() {
+        Ok(precision) => check_precision(precision, flag),
+        _ => Err(invalid_precision(&precision, flag)),
+    }
+}
+
+fn check_precision(precision: u32, flag: &str) -> Result<u32, String> {
+    if precision <= engine::MAX_PRECISION {
+        Ok(precision)
+    } else {
+        Err(invalid_precision(&precision, flag))
+    }
+}
+
+fn invalid_precision(precision: &dyn std::fmt::Display, flag: &str) -> String {
+    format!(
+        "invalid precision \"{}\" for \"{}\", expected 0 to {}",
+        precision,
+        flag,
+        engine::MAX_PRECISION
+    )
+}
+
+fn check_overdraft(overdraft: Decimal, flag: &str) -> Result<Decimal, String> {
+    if overdraft.is_sign_negative() {
+        Err(format!("invalid amount \"{}\" for \"{}\"", overdraft, flag))
+    } else {
+        Ok(overdraft)
+    }
+}
+
+fn dispute_policy(policy: &str) -> Result<DisputePolicy, String> {
+    match policy {
+        "strict" => Ok(DisputePolicy::StrictAvailable),
+        "allow-negative" => Ok(DisputePolicy::AllowNegative),
+        policy => Err(format!("unknown dispute policy \"{}\"", policy)),
+    }
+}
+
+/// Defaults read from `--config <file.toml>`, named like the flags they stand
+/// for; flags on the command line override them, e.g.
+///
+/// ```toml
+/// precision = 2
+/// dispute-policy = "allow-negative"
+/// overdraft = "50"
+///
+/// [output]
+/// errors = true
+/// totals-row = true
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub precision: Option<u32>,
+    pub dispute_policy: Option<String>,
+    pub overdraft: Option<Decimal>,
+    pub output: OutputDefaults,
+}
+
+/// The `[output]` table of a `Config`.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OutputDefaults {
+    /// Print transaction errors ahead of the accounts, like the `true`
+    /// argument.
+    pub errors: Option<bool>,
+    pub verbose: Option<bool>,
+    pub totals_row: Option<bool>,
+    pub available_precision: Option<u32>,
+    pub held_precision: Option<u32>,
+    pub pad_width: Option<usize>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("error reading config \"{}\": {}", path, err))?;
+        toml::from_str(&contents)
+            .map_err(|err| format!("error parsing config \"{}\": {}", path, err))
+    }
+
+    /// The options before any flag is applied.
+    pub fn options(&self) -> Result<Options, String> {
+        let mut options = Options::default();
+        if let Some(precision) = self.precision {
+            options.precision = check_precision(precision, "precision")?;
+        }
+        if let Some(policy) = &self.dispute_policy {
+            options.dispute_policy = dispute_policy(policy)?;
+        }
+        if let Some(overdraft) = self.overdraft {
+            options.overdraft = check_overdraft(overdraft, "overdraft")?;
+        }
+        let output = &self.output;
+        options.output_tx_errs = output.errors.unwrap_or(options.output_tx_errs);
+        options.verbose = output.verbose.unwrap_or(options.verbose);
+        options.totals_row = output.totals_row.unwrap_or(options.totals_row);
+        if let Some(precision) = output.available_precision {
+            options.available_precision = Some(check_precision(precision, "available-precision")?);
+        }
+        if let Some(precision) = output.held_precision {
+            options.held_precision = Some(check_precision(precision, "held-precision")?);
+        }
+        options.pad_width = output.pad_width.or(options.pad_width);
+        Ok(options)
+    }
+}
+
+/// Runs the CLI with the given arguments (without the program name), writing
+/// to `out` and returning the process exit code.
+pub fn run<W: Write>(args: &[String], out: &mut W) -> i32 {
+    let result = match args.first().map(String::as_str) {
+        Some("gen") => generate(&args[1..], out),
+        Some("diff") => diff(&args[1..], out),
+        _ => match Options::parse(args) {
+            // Failures only show in the exit code.
+            Ok(options) if options.quiet => return execute(&options, out).unwrap_or(1),
+            parsed => parsed
+                .map_err(|err| format!("error parsing arguments: {}", err))
+                .and_then(|options| execute(&options, out)),
+        },
+    };
+    match result {
+        Ok(code) => code,
+        Err(err) => {
+            let _ = writeln!(out, "{}", err);
+            1
+        }
+    }
+}
+
+/// Hidden `gen <seed> <rows>` subcommand writing synthetic transactions, e.g.
+/// for load testing.
+fn generate<W: Write>(args: &[String], out: &mut W) -> Result<i32, String> {
+    let (seed, rows) = match args {
+        [seed, rows] => (seed.parse().ok(), rows.parse().ok()),
+        _ => (None, None),
+    };
+    match (seed, rows) {
+        (Some(seed), Some(rows)) => {
+            generator::generate(out, seed, rows).map_err(|err| err.to_string())?;
+            Ok(0)
+        }
+        _ => Err("usage: gen <seed> <rows>".to_string()),
+    }
+}
+
+/// `diff <first.csv> <second.csv>` subcommand comparing two account
+/// snapshots, e.g. the output of two runs, exiting with 1 if they differ.
+fn diff<W: Write>(args: &[String], out: &mut W) -> Result<i32, String> {
+    let (first, second) = match args {
+        [first, second] => (first, second),
+        _ => return Err("usage: diff <first.csv> <second.csv>".to_string()),
+    };
+    let load = |path: &String| {
+        helpers::load_snapshot(path)
+            .map_err(|err| format!("error reading snapshot \"{}\": {}", path, err))
+    };
+    let diffs = diff::diff_accounts(&load(first)?, &load(second)?);
+    diff::write_diff(out, &diffs).map_err(|err| err.to_string())?;
+    Ok(if diffs.is_empty() { 0 } else { 1 })
+}
+
+fn execute<W: Write>(options: &Options, out: &mut W) -> Result<i32, String> {
+    if let Some(addr) = &options.listen {
+        return listen(addr, options);
+    }
+    let input = match &options.input {
+        Some(input) if input == "-" => input,
+        Some(input) if Path::new(input).is_dir() => {
+            return Err(format!(
+                "input \"{}\" is a directory, expected a *.csv file",
+                input
+            ));
+        }
+        Some(input)
+            if options.input_format == helpers::InputFormat::Ndjson
+                || helpers::is_supported_input(input) =>
+        {
+            input
+        }
+        _ => return Err("*.csv input file not found".to_string()),
+    };
+    let progress: Option<helpers::SharedProgress> =
+        (options.progress && !options.quiet).then(|| {
+            let stderr: Box<dyn Write + Send> = Box::new(io::stderr());
+            Arc::new(Mutex::new(helpers::Progress::new(
+                stderr,
+                PROGRESS_ROWS,
+                PROGRESS_INTERVAL,
+            )))
+        });
+    let parse_config = helpers::ParseConfig {
+        progress: progress.clone(),
+        ..parse_config(options)
+    };
+    let parsed = if input == "-" {
+        helpers::process_stream(std::io::stdin().lock(), &parse_config)
+    } else {
+        helpers::process_csv(input, &parse_config)
+    };
+    let (mut txs, parse_errs) = parsed.map_err(|err| format!("error parsing csv: {}", err))?;
+    if let Some(Ok(mut progress)) = progress.as_ref().map(|progress| progress.lock()) {
+        let _ = progress.finish();
+    }
+    // Skipped zip entries are reported, but aren't malformed rows.
+    let (warnings, mut parse_errs): (Vec<String>, Vec<String>) = parse_errs
+        .into_iter()
+        .partition(|err| err.starts_with("warning: "));
+    for warning in warnings {
+        diagnostic!(options, "{}", warning);
+    }
+    if txs.is_empty() && parse_errs.is_empty() {
+        diagnostic!(options, "input \"{}\" contains no transactions", input);
+    }
+
+    engine::sort_by_sequence(&mut txs);
+
+    if let Some(path) = &options.allowlist {
+        let allowlist = helpers::load_allowlist(path)
+            .map_err(|err| format!("error reading allowlist: {}", err))?;
+        let (allowed, skipped) = engine::retain_allowed(txs, &allowlist);
+        if skipped > 0 {
+            diagnostic!(
+                options,
+                "skipped {} transactions for clients not on the allowlist",
+                skipped
+            );
+        }
+        txs = allowed;
+    }
+
+    if options.snapshot.is_some() && options.load_state.is_some() {
+        return Err("--snapshot and --load-state cannot be combined".to_string());
+    }
+    let snapshot = match &options.snapshot {
+        Some(path) => Some(
+            helpers::load_snapshot(path)
+                .map_err(|err| format!("error reading snapshot: {}", err))?,
+        ),
+        None => None,
+    };
+
+    let config = engine_config(options);
+    let mut engine = match &options.load_state {
+        Some(path) => {
+            let file = File::open(path).map_err(|err| format!("error reading state: {}", err))?;
+            engine::Engine::load_state(BufReader::new(file), config)
+                .map_err(|err| format!("error reading state: {}", err))?
+        }
+        None => engine::Engine::from_accounts(snapshot.clone().unwrap_or_default(), config),
+    };
+    // Accounts as they were before this run, for --changed-only.
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None if options.load_state.is_some() => engine.accounts(),
+        None => Vec::new(),
+    };
+    let started = Instant::now();
+    let tx_count = txs.len();
+    for tx in txs {
+        match engine.apply(tx) {
+            Err(err @ engine::EngineError::UnhandledType(_)) if options.strict_types => {
+                return Err(format!("error processing transactions: {}", err));
+            }
+            _ => {}
+        }
+    }
+    engine.retry_deferred();
+    if options.benchmark {
+        let _ = engine.finish();
+        writeln!(
+            out,
+            "processed {} transactions in {:?}",
+            tx_count,
+            started.elapsed()
+        )
+        .map_err(|err| err.to_string())?;
+        return Ok(0);
+    }
+    for alert in engine.alerts() {
+        diagnostic!(options, "{}", alert);
+    }
+    for warning in engine.warnings() {
+        diagnostic!(options, "{}", warning);
+    }
+    if let Some(count) = options.largest_undisputed {
+        for movement in engine.largest_undisputed(count) {
+            diagnostic!(
+                options,
+                "undisputed {}: client {} tx {} amount {}",
+                movement.transaction_type,
+                movement.client,
+                movement.tx,
+                movement.amount
+            );
+        }
+    }
+    if let Some(path) = &options.history {
+        let history = engine.history_json().map_err(|err| err.to_string())?;
+        fs::write(path, history).map_err(|err| format!("error writing history: {}", err))?;
+    }
+    if let Some(path) = &options.trace {
+        let trace: String = engine
+            .trace()
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect();
+        fs::write(path, trace).map_err(|err| format!("error writing trace: {}", err))?;
+    }
+    if let Some(path) = &options.save_state {
+        let file = File::create(path).map_err(|err| format!("error writing state: {}", err))?;
+        engine
+            .save_state(BufWriter::new(file))
+            .map_err(|err| format!("error writing state: {}", err))?;
+    }
+    let by_client = options.group_errors.then(|| engine.errors_by_client());
+    let (mut processed_txs, mut tx_errs) = engine.finish();
+    if let Some(by_client) = by_client {
+        tx_errs = group_errors(by_client);
+    }
+    let locked: Vec<String> = processed_txs
+        .iter()
+        .filter(|account| account.locked)
+        .map(|account| account.client.to_string())
+        .collect();
+    let code = if options.fail_on_locked && !locked.is_empty() {
+        diagnostic!(options, "locked accounts: {}", locked.join(", "));
+        1
+    } else {
+        0
+    };
+    if options.report_json {
+        let errors = parse_errs.len() + tx_errs.len();
+        let report = helpers::Report::new(tx_count, errors, &processed_txs);
+        diagnostic!(
+            options,
+            "{}",
+            serde_json::to_string(&report).map_err(|err| err.to_string())?
+        );
+    }
+    if options.changed_only {
+        processed_txs = helpers::changed_accounts(processed_txs, &snapshot);
+    }
+    if options.omit_zero_accounts {
+        processed_txs = helpers::omit_zero_accounts(processed_txs);
+    }
+    if !options.clients.is_empty() {
+        let (selected, missing) = helpers::select_clients(processed_txs, &options.clients);
+        for client in missing {
+            diagnostic!(options, "warning: client {} not found", client);
+        }
+        processed_txs = selected;
+    }
+    parse_errs.extend(tx_errs);
+
+    if options.validate {
+        let error_count = parse_errs.len();
+        if options.output_tx_errs {
+            for err in parse_errs {
+                writeln!(out, "{}", err).map_err(|err| err.to_string())?;
+            }
+        }
+        writeln!(out, "{} errors", error_count).map_err(|err| err.to_string())?;
+        return Ok(if error_count == 0 { 0 } else { 1 });
+    }
+
+    let output_config = helpers::OutputConfig {
+        currency: processed_txs
+            .iter()
+            .any(|account| account.currency.is_some()),
+        ..output_config(options)
+    };
+    if let Some(dir) = &options.split_output {
+        if options.output_tx_errs {
+            for err in parse_errs {
+                writeln!(out, "{}", err).map_err(|err| err.to_string())?;
+            }
+        }
+        helpers::write_split_output(Path::new(dir), &processed_txs, &output_config)
+            .map_err(|err| format!("error writing output: {}", err))?;
+        return Ok(code);
+    }
+    match &options.output {
+        Some(path) => write_output_file(
+            Path::new(path),
+            processed_txs,
+            parse_errs,
+            &output_config,
+            options.write_retries,
+        ),
+        None => helpers::process_output(
+            &mut helpers::RetryWriter::new(out, options.write_retries),
+            processed_txs,
+            parse_errs,
+            &output_config,
+        ),
+    }
+    .or_else(|err| match err.kind() {
+        // The reader went away, e.g. `| head`, so there is no one to tell.
+        io::ErrorKind::BrokenPipe => Ok(()),
+        _ => Err(format!("error writing output: {}", err)),
+    })?;
+    Ok(code)
+}
+
+/// Reorders the engine's errors for `--group-errors`: the rejections by
+/// client id, each line prefixed with its client.
+fn group_errors(mut by_client: HashMap<u16, Vec<engine::EngineError>>) -> Vec<String> {
+    let mut grouped = Vec::new();
+    let mut clients: Vec<u16> = by_client.keys().copied().collect();
+    clients.sort_unstable();
+    for client in clients {
+        for err in by_client.remove(&client).unwrap_or_default() {
+            grouped.push(format!("client {}: {}", client, err));
+        }
+    }
+    grouped
+}
+
+fn engine_config(options: &Options) -> engine::EngineConfig {
+    engine::EngineConfig {
+        allow_redispute: options.allow_redispute,
+        allow_locked_resolve: options.allow_locked_resolve,
+        record_history: options.history.is_some(),
+        large_deposit_threshold: options.large_deposit_alert,
+        overdraft: options.overdraft,
+        precision: options.precision,
+        order: options.order,
+        normalize_balances: options.normalize_balances,
+        verify_amounts: options.verify_amounts,
+        dispute_policy: options.dispute_policy,
+        partial_chargebacks: options.partial_chargebacks,
+        track_undisputed: options.largest_undisputed.is_some(),
+        aliases: options.aliases.clone(),
+        record_trace: options.trace.is_some(),
+        defer_unresolved: options.defer_unresolved,
+        max_clients: options.max_clients,
+        reserve_client_zero: options.reserve_zero,
+    }
+}
+
+fn parse_config(options: &Options) -> helpers::ParseConfig {
+    helpers::ParseConfig {
+        format: options.input_format,
+        strict: options.strict,
+        limit: options.limit,
+        // Amounts with more places than the default are accepted as far as
+        // `--precision` keeps them.
+        max_scale: options.precision.max(MAX_AMOUNT_SCALE),
+        progress: None,
+    }
+}
+
+fn output_config(options: &Options) -> helpers::OutputConfig {
+    helpers::OutputConfig {
+        output_tx_errs: options.output_tx_errs,
+        verbose: options.verbose,
+        precision: options.precision,
+        available_precision: options.available_precision,
+        held_precision: options.held_precision,
+        totals_row: options.totals_row,
+        clamp_negative_dust: options.clamp_negative_dust,
+        pad_width: options.pad_width,
+        currency: false,
+    }
+}
+
+/// Serves `--listen` connections with one engine shared by all of them,
+/// until the listener fails.
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+fn listen(addr: &str, options: &Options) -> Result<i32, String> {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let config = engine_config(options);
+    let parse = parse_config(options);
+    let output = output_config(options);
+    let addr = addr.to_string();
+    diagnostic!(options, "listening on {}", addr);
+    // `run` may itself be called on a runtime thread, where a nested runtime
+    // can't block, so the server gets a thread of its own.
+    let served = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let engine = Rc::new(RefCell::new(engine::Engine::new(config)));
+        tokio::task::LocalSet::new().block_on(
+            &runtime,
+            crate::server::listen(&addr, engine, parse, output),
+        )
+    })
+    .join()
+    .map_err(|_| "server thread panicked".to_string())?;
+    served.map_err(|err| format!("error serving: {}", err))?;
+    Ok(0)
+}
+
+#[cfg(not(all(feature = "server", not(target_arch = "wasm32"))))]
+fn listen(_addr: &str, _options: &Options) -> Result<i32, String> {
+    Err("--listen requires the server feature".to_string())
+}
+
+/// Writes the accounts to `path` instead of stdout, creating its parent
+/// directories if needed.
+fn write_output_file(
+    path: &Path,
+    accounts: Vec<Account>,
+    errors: Vec<String>,
+    config: &helpers::OutputConfig,
+    retries: u32,
+) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut out = BufWriter::new(helpers::RetryWriter::new(File::create(path)?, retries));
+    helpers::process_output(&mut out, accounts, errors, config)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_input(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "toy-transactions-engine-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn run_with(args: &[&str]) -> (i32, String) {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut out = Vec::new();
+        let code = run(&args, &mut out);
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_validate_reports_errors() {
+        let input = write_input(
+            "validate.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,20.0\n",
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap(), "--validate"]);
+
+        assert_ne!(code, 0);
+        assert_eq!(output, "1 errors\n");
+    }
+
+    #[test]
+    fn test_diff() {
+        let first = write_input(
+            "diff-first.csv",
+            "client,available,held,total,locked\n1,10,0,10,false\n2,5,0,5,false\n",
+        );
+        let second = write_input(
+            "diff-second.csv",
+            "client,available,held,total,locked\n1,10,0,10,false\n2,0,5,5,false\n",
+        );
+        let first = first.to_str().unwrap();
+        let second = second.to_str().unwrap();
+
+        let (code, output) = run_with(&["diff", first, second]);
+        assert_eq!(code, 1);
+        assert_eq!(output, "client 2: available 5 -> 0, held 0 -> 5\n");
+
+        let (code, output) = run_with(&["diff", first, first]);
+        assert_eq!(code, 0);
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn test_benchmark_mode() {
+        let input = write_input(
+            "benchmark.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,1.0\n",
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap(), "--benchmark-mode"]);
+
+        assert_eq!(code, 0);
+        assert!(output.starts_with("processed 3 transactions in "));
+        assert!(!output.contains("client,available"));
+        assert_eq!(output.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut contents = String::from("type,client,tx,amount\n");
+        for tx in 1..=100 {
+            contents.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        let input = write_input("limit.csv", &contents);
+
+        let (code, output) = run_with(&[input.to_str().unwrap(), "--limit", "10"]);
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,10,0,10,false\n"
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        let input = write_input(
+            "aliases.csv",
+            "type,client,tx,amount\ncredit,1,1,10.0\ndebit,1,2,4.0\n",
+        );
+
+        let (code, output) = run_with(&[
+            input.to_str().unwrap(),
+            "--alias",
+            "credit=deposit",
+            "--alias",
+            "debit=withdrawal",
+        ]);
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,6,0,6,false\n"
+        );
+    }
+
+    #[test]
+    fn test_unaliased_type_errors() {
+        let input = write_input(
+            "unaliased.csv",
+            "type,client,tx,amount\ncredit,1,1,10.0\ndebit,1,2,4.0\n",
+        );
+
+        let (code, output) =
+            run_with(&[input.to_str().unwrap(), "true", "--alias", "credit=deposit"]);
+
+        assert_eq!(code, 0);
+        assert!(output.contains("Unhandled transaction type: \"debit\""));
+        assert!(output.contains("1,10,0,10,false"));
+    }
+
+    #[test]
+    fn test_trace_file() {
+        let input = write_input(
+            "trace.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\nchargeback,1,1,\n",
+        );
+        let trace = input.with_extension("trace");
+
+        let (code, _) = run_with(&[input.to_str().unwrap(), "--trace", trace.to_str().unwrap()]);
+
+        assert_eq!(code, 0);
+        let trace = fs::read_to_string(trace).unwrap();
+        let balances: Vec<&str> = trace
+            .lines()
+            .map(|line| line.split(": ").nth(1).unwrap())
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                "available 0 -> 10, held 0 -> 0, total 0 -> 10, locked false -> false",
+                "available 10 -> 0, held 0 -> 10, total 10 -> 10, locked false -> false",
+                "available 0 -> 0, held 10 -> 0, total 10 -> 0, locked false -> true",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_currencies() {
+        let input = write_input(
+            "currencies.csv",
+            "type,client,tx,amount,currency\n\
+             deposit,1,1,10.0,USD\n\
+             deposit,1,2,5.0,EUR\n\
+             withdrawal,1,3,2.0,USD\n\
+             dispute,1,2,,\n",
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap()]);
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            output,
+            "client,currency,available,held,total,locked\n\
+             1,EUR,0,5,5,false\n\
+             1,USD,8,0,8,false\n"
+        );
+    }
+
+    #[test]
+    fn test_strict_types() {
+        let input = write_input(
+            "strict-types.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\nrefund,1,2,5.0\ndeposit,1,3,1.0\n",
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap()]);
+        assert_eq!(code, 0);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,11,0,11,false\n"
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap(), "--strict-types"]);
+        assert_eq!(code, 1);
+        assert_eq!(
+            output,
+            "error processing transactions: Unhandled transaction type: \"refund\"\n"
+        );
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let input = write_input(
+            "max-clients.csv",
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,3,3,3.0\ndeposit,1,4,1.0\n",
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap(), "true", "--max-clients", "2"]);
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            output,
+            "Could not open an account for client 3: limit of 2 clients reached\n\
+             client,available,held,total,locked\n1,2,0,2,false\n2,2,0,2,false\n"
+        );
+    }
+
+    #[test]
+    fn test_group_errors() {
+        let input = write_input(
+            "group-errors.csv",
+            "type,client,tx,amount\ndeposit,2,1,5.0\nwithdrawal,1,2,1.0\n\
+             withdrawal,2,3,9.0\ndispute,1,2,\n",
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap(), "true", "--group-errors"]);
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            output,
+            "client 1: Error when handling transaction \"2\": Insufficient available funds\n\
+             client 1: Could not find applied transaction \"2\" to dispute\n\
+             client 2: Error when handling transaction \"3\": Insufficient available funds\n\
+             client,available,held,total,locked\n1,0,0,0,false\n2,5,0,5,false\n"
+        );
+    }
+
+    #[test]
+    fn test_eight_decimal_precision() {
+        let input = write_input(
+            "eight-decimals.csv",
+            "type,client,tx,amount\ndeposit,1,1,0.12345678\nwithdrawal,1,2,0.00000001\n",
+        );
+
+        let (_, output) = run_with(&[input.to_str().unwrap(), "true", "--precision", "8"]);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,0.12345677,0,0.12345677,false\n"
+        );
+
+        // At the default precision such amounts are malformed, not rounded.
+        let (_, output) = run_with(&[input.to_str().unwrap(), "true"]);
+        assert!(output.starts_with("Skipping malformed row at line 2: amount 0.12345678"));
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config = write_input(
+            "engine.toml",
+            "precision = 2\ndispute-policy = \"allow-negative\"\n\n[output]\nerrors = true\n",
+        );
+        let input = write_input(
+            "config.csv",
+            "type,client,tx,amount\ndeposit,1,1,1.2345\nwithdrawal,1,2,5\n",
+        );
+        let config = config.to_str().unwrap();
+        let input = input.to_str().unwrap();
+
+        let (_, output) = run_with(&[input, "--config", config]);
+        assert_eq!(
+            output,
+            "Error when handling transaction \"2\": Insufficient available funds\n\
+             client,available,held,total,locked\n1,1.23,0,1.23,false\n"
+        );
+        let options = Options::parse(&[
+            input.to_string(),
+            "--config".to_string(),
+            config.to_string(),
+        ])
+        .unwrap();
+        assert_eq!(options.dispute_policy, DisputePolicy::AllowNegative);
+
+        // Flags override the file, wherever they are given.
+        let (_, output) = run_with(&["--precision", "4", input, "--config", config, "false"]);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.2345,0,1.2345,false\n"
+        );
+
+        let bad = write_input("bad.toml", "precision = 9\n");
+        let (code, output) = run_with(&[input, "--config", bad.to_str().unwrap()]);
+        assert_eq!(code, 1);
+        assert_eq!(
+            output,
+            "error parsing arguments: invalid precision \"9\" for \"precision\", expected 0 to 8\n"
+        );
+    }
+
+    #[test]
+    fn test_negative_withdrawal() {
+        let input = write_input(
+            "negative-withdrawal.csv",
+            "type,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,-50\n",
+        );
+
+        let (code, output) = run_with(&[input.to_str().unwrap(), "true"]);
+
+        assert_eq!(code, 0);
+        assert_eq!(
+            output,
+            "Negative amount -50 for withdrawal transaction \"2\"\n\
+             client,available,held,total,locked\n1,100,0,100,false\n"
+        );
+    }
+
+    #[test]
+    fn test_output_file() {
+        let input = write_input(
+            "output.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.5\ndeposit,2,2,3\n",
+        );
+        let output = std::env::temp_dir().join(format!(
+            "toy-transactions-engine-{}-out/accounts.csv",
+            std::process::id()
+        ));
+
+        let (code, stdout) = run_with(&[
+            input.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+        ]);
+
+        assert_eq!(code, 0);
+        assert_eq!(stdout, "");
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "client,available,held,total,locked\n1,10.5,0,10.5,false\n2,3,0,3,false\n"
+        );
+        fs::remove_dir_all(output.parent().unwrap()).unwrap();
+    }
+
+    /// Fails every write, like stdout once the reading end of a pipe closed.
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_broken_pipe() {
+        let input = write_input(
+            "broken-pipe.csv",
+            "type,client,tx,amount\ndeposit,1,1,1.0\n",
+        );
+        let args = vec![input.to_str().unwrap().to_string()];
+
+        assert_eq!(run(&args, &mut ClosedPipe), 0);
+    }
+
+    #[test]
+    fn test_output_file_error() {
+        let input = write_input("output-error.csv", "type,client,tx,amount\n");
+        let dir = std::env::temp_dir();
+
+        let (code, stdout) =
+            run_with(&[input.to_str().unwrap(), "--output", dir.to_str().unwrap()]);
+
+        assert_eq!(code, 1);
+        assert!(stdout.starts_with("error writing output: "));
+    }
+
+    #[test]
+    fn test_directory_input() {
+        let dir = std::env::temp_dir();
+
+        let (code, output) = run_with(&[dir.to_str().unwrap()]);
+
+        assert_eq!(code, 1);
+        assert_eq!(
+            output,
+            format!(
+                "input \"{}\" is a directory, expected a *.csv file\n",
+                dir.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_precision() {
+        let input = write_input(
+            "precision.csv",
+            "type,client,tx,amount\ndeposit,1,1,1.2345\ndeposit,1,2,1.0051\n",
+        );
+        let input = input.to_str().unwrap();
+
+        assert_eq!(
+            run_with(&[input, "--precision", "4"]).1,
+            "client,available,held,total,locked\n1,2.2396,0,2.2396,false\n"
+        );
+        assert_eq!(
+            run_with(&[input, "--precision", "2"]).1,
+            "client,available,held,total,locked\n1,2.24,0,2.24,false\n"
+        );
+        assert_eq!(run_with(&[input, "--precision", "9"]).0, 1);
+    }
+}
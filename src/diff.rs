@@ -0,0 +1,141 @@
+use crate::types::Account;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// A client (per currency) whose account differs between two snapshots;
+/// either side is `None` when the client is missing from that snapshot.
+#[derive(Debug, PartialEq)]
+pub struct AccountDiff {
+    pub client: u16,
+    pub currency: Option<String>,
+    pub before: Option<Account>,
+    pub after: Option<Account>,
+}
+
+impl AccountDiff {
+    /// The differing fields as `available 5 -> 3`, in output column order.
+    pub fn changes(&self) -> Vec<String> {
+        let (before, after) = match (&self.before, &self.after) {
+            (Some(before), Some(after)) => (before, after),
+            _ => return Vec::new(),
+        };
+        let mut changes = Vec::new();
+        if before.available != after.available {
+            changes.push(format!(
+                "available {} -> {}",
+                before.available, after.available
+            ));
+        }
+        if before.held != after.held {
+            changes.push(format!("held {} -> {}", before.held, after.held));
+        }
+        if before.total != after.total {
+            changes.push(format!("total {} -> {}", before.total, after.total));
+        }
+        if before.locked != after.locked {
+            changes.push(format!("locked {} -> {}", before.locked, after.locked));
+        }
+        changes
+    }
+}
+
+type Key = (u16, Option<String>);
+
+/// Compares two account snapshots, e.g. the output of two runs, returning the
+/// accounts that differ in available, held, total or locked, by client id.
+/// Audit columns are ignored, as for `Account`'s equality.
+pub fn diff_accounts(before: &[Account], after: &[Account]) -> Vec<AccountDiff> {
+    // Both sides of each client, keyed by client and currency.
+    let mut accounts: BTreeMap<Key, (Option<&Account>, Option<&Account>)> = BTreeMap::new();
+    for account in before {
+        accounts
+            .entry((account.client, account.currency.clone()))
+            .or_default()
+            .0 = Some(account);
+    }
+    for account in after {
+        accounts
+            .entry((account.client, account.currency.clone()))
+            .or_default()
+            .1 = Some(account);
+    }
+    accounts
+        .into_iter()
+        .filter(|(_, (before, after))| before != after)
+        .map(|((client, currency), (before, after))| AccountDiff {
+            client,
+            currency,
+            before: before.cloned(),
+            after: after.cloned(),
+        })
+        .collect()
+}
+
+/// Writes a line per differing account, e.g.
+/// `client 2: available 5 -> 3, total 5 -> 3` or `client 4: only in second`.
+pub fn write_diff<W: Write>(out: &mut W, diffs: &[AccountDiff]) -> io::Result<()> {
+    for diff in diffs {
+        let client = match &diff.currency {
+            Some(currency) => format!("client {} {}", diff.client, currency),
+            None => format!("client {}", diff.client),
+        };
+        match (&diff.before, &diff.after) {
+            (Some(_), None) => writeln!(out, "{}: only in first", client)?,
+            (None, Some(_)) => writeln!(out, "{}: only in second", client)?,
+            _ => writeln!(out, "{}: {}", client, diff.changes().join(", "))?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::read_snapshot;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_diff_accounts() {
+        let before = read_snapshot(
+            "client,available,held,total,locked
+1,10,0,10,false
+2,5,0,5,false
+3,1,1,2,false
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let after = read_snapshot(
+            "client,available,held,total,locked
+3,1.0,1,2,false
+2,3,0,3,true
+1,10,0,10,false
+"
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let diffs = diff_accounts(&before, &after);
+
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].client, 2);
+        assert_eq!(
+            diffs[0].after,
+            Some(Account::new(2, dec!(3), dec!(0), true))
+        );
+        let mut out = Vec::new();
+        write_diff(&mut out, &diffs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client 2: available 5 -> 3, total 5 -> 3, locked false -> true\n"
+        );
+
+        let diffs = diff_accounts(&before[..1], &after[..1]);
+        let mut out = Vec::new();
+        write_diff(&mut out, &diffs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client 1: only in first\nclient 3: only in second\n"
+        );
+    }
+}
@@ -1,125 +1,1927 @@
-use crate::types::{Account, Transaction};
+use crate::types::{round_amount, Account, Amount, DisputePolicy, Transaction};
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::io::{Read, Write};
 
-pub fn process_transactions(transactions: Vec<Transaction>) -> (Vec<Account>, Vec<String>) {
-    let mut accounts: HashMap<u16, Account> = HashMap::new();
-    let mut applied_txs: HashMap<u32, Decimal> = HashMap::new();
-    let mut disputed_txs: HashMap<u32, Decimal> = HashMap::new();
-    let mut tx_errors: Vec<String> = Vec::new();
+pub fn retain_allowed(
+    transactions: Vec<Transaction>,
+    allowlist: &HashSet<u16>,
+) -> (Vec<Transaction>, usize) {
+    let total = transactions.len();
+    let allowed: Vec<Transaction> = transactions
+        .into_iter()
+        .filter(|transaction| allowlist.contains(&transaction.client))
+        .collect();
+    let skipped = total - allowed.len();
+    (allowed, skipped)
+}
 
-    for transaction in transactions {
-        let account = accounts
-            .entry(transaction.client)
-            .or_insert_with(|| Account::empty(transaction.client));
+/// Orders transactions by their `sequence` number, so that e.g. merged inputs
+/// are applied canonically. Transactions without one follow in input order;
+/// the order is left untouched when no transaction has one.
+pub fn sort_by_sequence(transactions: &mut [Transaction]) {
+    if transactions
+        .iter()
+        .any(|transaction| transaction.sequence.is_some())
+    {
+        transactions.sort_by_key(|transaction| transaction.sequence.unwrap_or(u64::MAX));
+    }
+}
 
-        match transaction.transaction_type.as_str() {
-            "deposit" => {
-                account.deposit(transaction.amount).unwrap();
-                applied_txs.insert(transaction.tx, transaction.amount);
+/// Map used for the engine's hot lookups by client and transaction id.
+#[cfg(feature = "fxhash")]
+type FastMap<K, V> = rustc_hash::FxHashMap<K, V>;
+#[cfg(not(feature = "fxhash"))]
+type FastMap<K, V> = HashMap<K, V>;
+
+/// Decimal places amounts are rounded to unless configured otherwise.
+pub const DEFAULT_PRECISION: u32 = 4;
+/// Largest supported `precision`.
+pub const MAX_PRECISION: u32 = 8;
+/// Largest amount a transaction may carry, far enough below `Decimal::MAX`
+/// (about 7.9e28) that no realistic number of them overflows a balance.
+pub const MAX_AMOUNT: Decimal = dec!(1_000_000_000_000_000_000);
+
+/// Order in which `Engine::finish` and `Engine::into_accounts` return accounts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputOrder {
+    /// Sorted by ascending client id, stable across runs.
+    ByClientId,
+    /// In the order each client was first seen.
+    FirstSeen,
+}
+
+/// Reason a transaction was rejected. Rejected transactions leave balances
+/// untouched.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError<A: Amount = Decimal> {
+    /// A dispute, resolve or chargeback for a client without an account.
+    ReferentialOnly {
+        client: u16,
+    },
+    MissingAmount {
+        transaction_type: String,
+        tx: u32,
+    },
+    /// A deposit, withdrawal, transfer or partial chargeback with a negative
+    /// amount.
+    NegativeAmount {
+        transaction_type: String,
+        tx: u32,
+        amount: Decimal,
+    },
+    WithdrawalRejected {
+        tx: u32,
+        reason: String,
+    },
+    /// A dispute referencing a transaction that was never applied.
+    UnknownTransaction {
+        tx: u32,
+    },
+    AlreadyDisputed {
+        tx: u32,
+    },
+    /// A deposit or withdrawal reusing the id of an applied transaction of
+    /// the same type.
+    DuplicateTransaction {
+        tx: u32,
+        transaction_type: String,
+    },
+    /// A deposit or withdrawal reusing the id of an applied transaction of
+    /// the other type, whose amount disputes would otherwise confuse.
+    TransactionTypeConflict {
+        tx: u32,
+        transaction_type: String,
+        original_type: &'static str,
+    },
+    AlreadyResolved {
+        tx: u32,
+    },
+    /// A dispute of a transaction whose whole amount was charged back.
+    FullyChargedBack {
+        tx: u32,
+    },
+    /// A dispute whose amount was already withdrawn or transferred out, so
+    /// holding it would leave available funds negative.
+    CannotDisputeSpentFunds {
+        tx: u32,
+        amount: A,
+        available: A,
+    },
+    DisputeRejected {
+        tx: u32,
+        reason: String,
+    },
+    /// A resolve or chargeback for a transaction that isn't under dispute.
+    NotDisputed {
+        tx: u32,
+        action: &'static str,
+    },
+    /// A chargeback reversal for a transaction that isn't charged back.
+    NotChargedBack {
+        tx: u32,
+    },
+    ResolveRejected {
+        tx: u32,
+        reason: String,
+    },
+    ChargebackRejected {
+        tx: u32,
+        reason: String,
+    },
+    IncompleteTransfer {
+        tx: u32,
+    },
+    SelfTransfer {
+        tx: u32,
+    },
+    TransferRejected {
+        tx: u32,
+        reason: String,
+    },
+    /// A resolve or chargeback whose amount differs from the disputed amount,
+    /// only checked with `verify_amounts`.
+    AmountMismatch {
+        tx: u32,
+        transaction_type: String,
+    },
+    /// A transaction that would open an account beyond `max_clients`.
+    TooManyClients {
+        client: u16,
+        max: usize,
+    },
+    /// A dispute on an account frozen by an earlier chargeback.
+    DisputeOnLockedAccount {
+        client: u16,
+    },
+    /// A resolve on an account frozen by the chargeback of another
+    /// transaction.
+    ResolveOnLockedAccount {
+        client: u16,
+        tx: u32,
+    },
+    /// A dispute, resolve or chargeback by a client other than the one the
+    /// transaction belongs to.
+    ForeignTransaction {
+        client: u16,
+        tx: u32,
+    },
+    /// A transaction from or to client 0 under `reserve_client_zero`.
+    ReservedClient {
+        tx: u32,
+    },
+    /// An amount above `MAX_AMOUNT`.
+    AmountOutOfRange {
+        transaction_type: String,
+        tx: u32,
+        amount: Decimal,
+    },
+    /// An amount the engine's `Amount` type can't hold at the configured
+    /// precision, e.g. above about 9.2e14 for `i64` at four places.
+    AmountOverflow {
+        transaction_type: String,
+        tx: u32,
+        amount: Decimal,
+    },
+    /// A transaction with a currency passed to
+    /// `process_transactions_with_state`, whose caller-owned accounts have
+    /// one balance per client.
+    CurrencyWithoutEngine {
+        tx: u32,
+    },
+    UnhandledType(String),
+}
+
+impl<A: Amount> fmt::Display for EngineError<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::ReferentialOnly { client } => {
+                write!(f, "client {} has only referential transactions", client)
             }
-            "withdrawal" => {
-                match account.withdraw(transaction.amount) {
-                    Ok(_) => applied_txs.insert(transaction.tx, transaction.amount),
-                    Err(err) => {
-                        tx_errors.push(format!(
-                            "Error when handling transaction \"{}\": {}",
-                            transaction.tx, err
-                        ));
-                        continue;
-                    }
-                };
+            EngineError::MissingAmount {
+                transaction_type,
+                tx,
+            } => write!(
+                f,
+                "Missing amount for {} transaction \"{}\"",
+                transaction_type, tx
+            ),
+            EngineError::NegativeAmount {
+                transaction_type,
+                tx,
+                amount,
+            } => write!(
+                f,
+                "Negative amount {} for {} transaction \"{}\"",
+                amount, transaction_type, tx
+            ),
+            EngineError::WithdrawalRejected { tx, reason } => {
+                write!(f, "Error when handling transaction \"{}\": {}", tx, reason)
             }
-            "dispute" => {
-                let disputable = match applied_txs.get(&transaction.tx) {
-                    Some(disputable) => disputable.clone(),
+            EngineError::DisputeOnLockedAccount { client } => {
+                write!(f, "Cannot dispute on locked account {}", client)
+            }
+            EngineError::ResolveOnLockedAccount { client, tx } => write!(
+                f,
+                "Cannot resolve transaction \"{}\" on locked account {}",
+                tx, client
+            ),
+            EngineError::ForeignTransaction { client, tx } => write!(
+                f,
+                "Could not apply transaction \"{}\": it does not belong to client {}",
+                tx, client
+            ),
+            EngineError::UnknownTransaction { tx } => write!(
+                f,
+                "Could not find applied transaction \"{}\" to dispute",
+                tx
+            ),
+            EngineError::AlreadyDisputed { tx } => {
+                write!(f, "Could not dispute same transaction \"{}\" twice", tx)
+            }
+            EngineError::DuplicateTransaction {
+                tx,
+                transaction_type,
+            } => write!(
+                f,
+                "Duplicate {} transaction \"{}\" already applied",
+                transaction_type, tx
+            ),
+            EngineError::TransactionTypeConflict {
+                tx,
+                transaction_type,
+                original_type,
+            } => write!(
+                f,
+                "Could not apply {} transaction \"{}\": id already used by a {}",
+                transaction_type, tx, original_type
+            ),
+            EngineError::AlreadyResolved { tx } => {
+                write!(f, "Could not dispute resolved transaction \"{}\" again", tx)
+            }
+            EngineError::FullyChargedBack { tx } => write!(
+                f,
+                "Could not dispute transaction \"{}\": it was fully charged back",
+                tx
+            ),
+            EngineError::CannotDisputeSpentFunds {
+                tx,
+                amount,
+                available,
+            } => write!(
+                f,
+                "Could not dispute transaction \"{}\": amount {} was already spent (available {})",
+                tx, amount, available
+            ),
+            EngineError::DisputeRejected { tx, reason } => {
+                write!(f, "Could not dispute transaction \"{}\": {}", tx, reason)
+            }
+            EngineError::NotDisputed { tx, action } => write!(
+                f,
+                "Could not find disputed transaction \"{}\" to {}",
+                tx, action
+            ),
+            EngineError::NotChargedBack { tx } => write!(
+                f,
+                "Could not find charged back transaction \"{}\" to reverse",
+                tx
+            ),
+            EngineError::ResolveRejected { tx, reason } => write!(
+                f,
+                "Could not resolve disputed transaction \"{}\": {}",
+                tx, reason
+            ),
+            EngineError::ChargebackRejected { tx, reason } => write!(
+                f,
+                "Could not charge back disputed transaction \"{}\": {}",
+                tx, reason
+            ),
+            EngineError::IncompleteTransfer { tx } => write!(
+                f,
+                "Missing amount or destination for transfer transaction \"{}\"",
+                tx
+            ),
+            EngineError::SelfTransfer { tx } => write!(
+                f,
+                "Could not transfer transaction \"{}\" to the same client",
+                tx
+            ),
+            EngineError::TransferRejected { tx, reason } => {
+                write!(f, "Could not transfer transaction \"{}\": {}", tx, reason)
+            }
+            EngineError::AmountMismatch {
+                tx,
+                transaction_type,
+            } => write!(f, "{} amount mismatch for tx {}", transaction_type, tx),
+            EngineError::TooManyClients { client, max } => write!(
+                f,
+                "Could not open an account for client {}: limit of {} clients reached",
+                client, max
+            ),
+            EngineError::ReservedClient { tx } => write!(
+                f,
+                "Could not apply transaction \"{}\": client 0 is reserved",
+                tx
+            ),
+            EngineError::AmountOutOfRange {
+                transaction_type,
+                tx,
+                amount,
+            } => write!(
+                f,
+                "Amount {} for {} transaction \"{}\" exceeds the maximum of {}",
+                amount, transaction_type, tx, MAX_AMOUNT
+            ),
+            EngineError::AmountOverflow {
+                transaction_type,
+                tx,
+                amount,
+            } => write!(
+                f,
+                "Amount {} for {} transaction \"{}\" does not fit the amount type",
+                amount, transaction_type, tx
+            ),
+            EngineError::CurrencyWithoutEngine { tx } => write!(
+                f,
+                "Could not apply transaction \"{}\": currencies need an Engine",
+                tx
+            ),
+            EngineError::UnhandledType(t) => write!(f, "Unhandled transaction type: \"{}\"", t),
+        }
+    }
+}
+
+impl<A: Amount> Error for EngineError<A> {}
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// Whether a transaction whose dispute was resolved may be disputed again.
+    pub allow_redispute: bool,
+    /// Whether a dispute may be resolved while another transaction's
+    /// chargeback keeps the account locked; by default the frozen account
+    /// rejects it. The remainder of a partial chargeback can always be
+    /// resolved.
+    pub allow_locked_resolve: bool,
+    /// Keep the ordered list of applied transactions per client.
+    pub record_history: bool,
+    /// Raise an alert for deposits above this amount.
+    pub large_deposit_threshold: Option<Decimal>,
+    /// How far withdrawals and outgoing transfers may take available funds
+    /// below zero.
+    pub overdraft: Decimal,
+    /// Decimal places transaction amounts are rounded to before being applied.
+    /// Trailing zeros are dropped too, so `100` and `100.0000` leave the same
+    /// balances, printed alike.
+    pub precision: u32,
+    pub order: OutputOrder,
+    /// Round touched balances back to `precision` after every transaction,
+    /// e.g. when snapshot balances carry more decimal places.
+    pub normalize_balances: bool,
+    /// Whether disputes may leave available funds negative.
+    pub dispute_policy: DisputePolicy,
+    /// Reject resolves and chargebacks carrying an amount other than the
+    /// disputed one; by default their amount is ignored.
+    pub verify_amounts: bool,
+    /// Let a chargeback carrying an amount reverse only that part of the
+    /// dispute, keeping the rest held until a later resolve or chargeback.
+    pub partial_chargebacks: bool,
+    /// Remember every deposit and withdrawal for `largest_undisputed`.
+    pub track_undisputed: bool,
+    /// Transaction type spellings mapped to the canonical ones before
+    /// processing, e.g. `credit` to `deposit`.
+    pub aliases: HashMap<String, String>,
+    /// Record the balances of the accounts each transaction touches before
+    /// and after it, for `trace`.
+    pub record_trace: bool,
+    /// Hold disputes of transactions not seen yet, along with the resolves
+    /// and chargebacks following them, and retry them in `finish` once all
+    /// other transactions were applied. The held rows stay in memory until
+    /// then.
+    pub defer_unresolved: bool,
+    /// Reject transactions that would open an account beyond this many, to
+    /// bound memory on hostile input; existing accounts keep processing.
+    /// Accounts in every currency count against the one limit.
+    pub max_clients: Option<usize>,
+    /// Reject transactions from or to client 0, for systems using it as a
+    /// sentinel.
+    pub reserve_client_zero: bool,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            allow_redispute: false,
+            allow_locked_resolve: false,
+            record_history: false,
+            large_deposit_threshold: None,
+            overdraft: Decimal::ZERO,
+            precision: DEFAULT_PRECISION,
+            order: OutputOrder::ByClientId,
+            normalize_balances: false,
+            verify_amounts: false,
+            dispute_policy: DisputePolicy::StrictAvailable,
+            partial_chargebacks: false,
+            track_undisputed: false,
+            aliases: HashMap::new(),
+            record_trace: false,
+            defer_unresolved: false,
+            max_clients: None,
+            reserve_client_zero: false,
+        }
+    }
+}
+
+/// Format version written by `Engine::save_state`. Bump it whenever
+/// `EngineState` changes in a way older or newer readers would misload.
+/// Version 2 added the owners of open disputes and the currency engines.
+pub const STATE_VERSION: u32 = 2;
+
+fn legacy_state_version() -> u32 {
+    1
+}
+
+/// Serializable engine state: account balances plus the dispute bookkeeping
+/// needed to keep disputing, resolving and charging back transactions from a
+/// previous run.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EngineState<A: Amount = Decimal> {
+    /// `STATE_VERSION` of the writer; states saved before the field existed
+    /// read as version 1.
+    #[serde(default = "legacy_state_version")]
+    pub version: u32,
+    pub accounts: Vec<Account<A>>,
+    pub applied_txs: HashMap<u32, A>,
+    pub disputed_txs: HashMap<u32, A>,
+    pub resolved_txs: HashSet<u32>,
+    /// Ids in `applied_txs` that were withdrawals rather than deposits.
+    #[serde(default)]
+    pub withdrawal_txs: HashSet<u32>,
+    /// Client and amount of each charged back transaction, by transaction id.
+    #[serde(default)]
+    pub charged_back_txs: HashMap<u32, (u16, A)>,
+    /// Client holding the funds of each open dispute, by transaction id.
+    #[serde(default)]
+    pub dispute_clients: HashMap<u32, u16>,
+    /// Client of each applied deposit and withdrawal, by transaction id.
+    #[serde(default)]
+    pub tx_clients: HashMap<u32, u16>,
+    /// State of the accounts kept per currency, by currency.
+    #[serde(default)]
+    pub by_currency: BTreeMap<String, EngineState<A>>,
+    /// Currency of each applied deposit and withdrawal that had one, by
+    /// transaction id.
+    #[serde(default)]
+    pub tx_currencies: HashMap<u32, String>,
+}
+
+/// Dispute bookkeeping owned by the caller of
+/// `process_transactions_with_state`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ledger {
+    /// Amounts of applied deposits and withdrawals, by transaction id.
+    pub applied_txs: HashMap<u32, Decimal>,
+    /// Amounts currently held by open disputes, by transaction id.
+    pub disputed_txs: HashMap<u32, Decimal>,
+    pub resolved_txs: HashSet<u32>,
+    /// Ids in `applied_txs` that were withdrawals rather than deposits.
+    pub withdrawal_txs: HashSet<u32>,
+    /// Client and amount of each charged back transaction, by transaction id.
+    pub charged_back_txs: HashMap<u32, (u16, Decimal)>,
+    /// Client holding the funds of each open dispute, by transaction id.
+    pub dispute_clients: HashMap<u32, u16>,
+    /// Client of each applied deposit and withdrawal, by transaction id.
+    pub tx_clients: HashMap<u32, u16>,
+}
+
+/// An applied deposit or withdrawal, kept with `track_undisputed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movement<A: Amount = Decimal> {
+    pub tx: u32,
+    pub client: u16,
+    pub transaction_type: String,
+    pub amount: A,
+    /// Whether the transaction has ever been disputed.
+    pub disputed: bool,
+}
+
+/// Streaming transactions engine: transactions are applied one at a time and
+/// the resulting accounts are collected with `finish`.
+pub struct Engine<A: Amount = Decimal> {
+    config: EngineConfig,
+    accounts: FastMap<u16, Account<A>>,
+    /// Client ids in the order their accounts were created.
+    order: Vec<u16>,
+    applied_txs: FastMap<u32, A>,
+    /// Client of each entry in `applied_txs`.
+    tx_clients: FastMap<u32, u16>,
+    disputed_txs: FastMap<u32, A>,
+    /// Client holding the funds of each entry in `disputed_txs`.
+    dispute_clients: FastMap<u32, u16>,
+    resolved_txs: HashSet<u32>,
+    /// Ids in `applied_txs` that were withdrawals rather than deposits.
+    withdrawal_txs: HashSet<u32>,
+    /// Client and amount of each charged back transaction, until reversed.
+    charged_back_txs: HashMap<u32, (u16, A)>,
+    /// Currency of the accounts kept here, unset for transactions without one.
+    currency: Option<String>,
+    /// Engines keeping the accounts of transactions with a currency, so each
+    /// client has separate balances per currency.
+    by_currency: BTreeMap<String, Engine<A>>,
+    /// Accounts kept outside this currency engine, by the engine owning it
+    /// and its other currencies, so that `max_clients` bounds them all.
+    other_accounts: usize,
+    /// Currency of each applied deposit and withdrawal that had one, for
+    /// routing the transactions referencing it.
+    tx_currencies: FastMap<u32, String>,
+    history: BTreeMap<u16, Vec<Transaction>>,
+    alerts: Vec<String>,
+    /// Applied transactions that look like data errors, such as a total
+    /// going negative; kept apart from `tx_errors`, as nothing was rejected.
+    warnings: Vec<String>,
+    trace: Vec<String>,
+    tx_errors: Vec<String>,
+    /// The rejections among `tx_errors`, by the client of the transaction.
+    client_errors: HashMap<u16, Vec<EngineError<A>>>,
+    movements: HashMap<u32, Movement<A>>,
+    before_apply: Option<BeforeApply<A>>,
+    /// Number of transactions passed to `apply` so far.
+    applied_count: u64,
+    /// Transactions held by `defer_unresolved` with their input index.
+    deferred: Vec<(u64, Transaction)>,
+    /// Ids of the transactions referenced by `deferred`.
+    deferred_txs: HashSet<u32>,
+}
+
+/// Callback run by `Engine::apply` before each transaction is processed.
+pub type BeforeApply<A = Decimal> = Box<dyn FnMut(&Engine<A>, &Transaction)>;
+
+impl Engine {
+    pub fn new(config: EngineConfig) -> Self {
+        Self::with_config(config)
+    }
+
+    /// Like `new`, but with room for `clients` accounts and `txs` transactions
+    /// so large inputs don't repeatedly grow the maps.
+    pub fn with_capacity(clients: usize, txs: usize, config: EngineConfig) -> Self {
+        let mut engine = Self::new(config);
+        engine.reserve(clients, txs);
+        engine
+    }
+
+    /// Resumes from a state written by `save_state`, e.g. a checkpoint taken
+    /// after each input file. Version 1 states are upgraded; states of any
+    /// other version than `STATE_VERSION` are rejected.
+    pub fn load_state<R: Read>(input: R, config: EngineConfig) -> serde_json::Result<Self> {
+        let state: EngineState = serde_json::from_reader(input)?;
+        if state.version != 1 && state.version != STATE_VERSION {
+            return Err(serde::de::Error::custom(format!(
+                "unsupported state version {} (expected {})",
+                state.version, STATE_VERSION
+            )));
+        }
+        Ok(Self::from_state(state, config))
+    }
+}
+
+impl<A: Amount> Engine<A> {
+    /// Like `new`, for any `Amount` type, e.g. `Engine::<i64>::with_config`
+    /// to keep balances in minor units.
+    pub fn with_config(config: EngineConfig) -> Self {
+        Self::from_accounts(Vec::new(), config)
+    }
+
+    fn reserve(&mut self, clients: usize, txs: usize) {
+        let clients = clients.min(usize::from(u16::MAX) + 1);
+        self.accounts.reserve(clients);
+        self.order.reserve(clients);
+        self.applied_txs.reserve(txs);
+    }
+
+    /// Starts from previously snapshotted account balances instead of empty
+    /// accounts.
+    pub fn from_accounts(snapshot: Vec<Account<A>>, config: EngineConfig) -> Self {
+        let (snapshot, in_currencies): (Vec<Account<A>>, Vec<Account<A>>) = snapshot
+            .into_iter()
+            .partition(|account| account.currency.is_none());
+        let mut engine = Self {
+            config,
+            order: snapshot.iter().map(|account| account.client).collect(),
+            accounts: snapshot
+                .into_iter()
+                .map(|account| (account.client, account))
+                .collect(),
+            applied_txs: FastMap::default(),
+            tx_clients: FastMap::default(),
+            disputed_txs: FastMap::default(),
+            dispute_clients: FastMap::default(),
+            resolved_txs: HashSet::new(),
+            withdrawal_txs: HashSet::new(),
+            charged_back_txs: HashMap::new(),
+            currency: None,
+            by_currency: BTreeMap::new(),
+            other_accounts: 0,
+            tx_currencies: FastMap::default(),
+            history: BTreeMap::new(),
+            alerts: Vec::new(),
+            warnings: Vec::new(),
+            trace: Vec::new(),
+            tx_errors: Vec::new(),
+            client_errors: HashMap::new(),
+            movements: HashMap::new(),
+            before_apply: None,
+            applied_count: 0,
+            deferred: Vec::new(),
+            deferred_txs: HashSet::new(),
+        };
+        for account in in_currencies {
+            let currency = account.currency.clone().unwrap_or_default();
+            let currency_engine = engine.currency_engine(currency);
+            currency_engine.order.push(account.client);
+            currency_engine.accounts.insert(account.client, account);
+        }
+        engine
+    }
+
+    /// Resumes from a state saved with `state`.
+    pub fn from_state(state: EngineState<A>, config: EngineConfig) -> Self {
+        let mut engine = Self::with_config(config);
+        engine.load(state);
+        engine
+    }
+
+    /// Fills this engine and its currency engines from `state`. Accounts with
+    /// another currency than this engine's go to that currency's engine, as
+    /// in `from_accounts`.
+    fn load(&mut self, state: EngineState<A>) {
+        for account in state.accounts {
+            let engine = match &account.currency {
+                Some(currency) if Some(currency) != self.currency.as_ref() => {
+                    self.currency_engine(currency.clone())
+                }
+                _ => &mut *self,
+            };
+            engine.order.push(account.client);
+            engine.accounts.insert(account.client, account);
+        }
+        self.applied_txs = state.applied_txs.into_iter().collect();
+        self.disputed_txs = state.disputed_txs.into_iter().collect();
+        self.dispute_clients = state.dispute_clients.into_iter().collect();
+        self.tx_clients = state.tx_clients.into_iter().collect();
+        if state.version == 1 {
+            // Disputes hold the funds of the disputed transaction's client.
+            for tx in self.disputed_txs.keys() {
+                if let Some(&client) = self.tx_clients.get(tx) {
+                    self.dispute_clients.entry(*tx).or_insert(client);
+                }
+            }
+        }
+        self.resolved_txs = state.resolved_txs;
+        self.withdrawal_txs = state.withdrawal_txs;
+        self.charged_back_txs = state.charged_back_txs;
+        self.tx_currencies = state.tx_currencies.into_iter().collect();
+        for (currency, state) in state.by_currency {
+            self.currency_engine(currency).load(state);
+        }
+    }
+
+    /// Accounts and dispute bookkeeping, including those of each currency.
+    pub fn state(&self) -> EngineState<A> {
+        EngineState {
+            version: STATE_VERSION,
+            accounts: self
+                .order
+                .iter()
+                .filter_map(|client| self.accounts.get(client))
+                .map(|account| Account {
+                    currency: self.currency.clone(),
+                    ..account.clone()
+                })
+                .collect(),
+            applied_txs: copy_map(&self.applied_txs),
+            disputed_txs: copy_map(&self.disputed_txs),
+            resolved_txs: self.resolved_txs.clone(),
+            withdrawal_txs: self.withdrawal_txs.clone(),
+            charged_back_txs: self.charged_back_txs.clone(),
+            dispute_clients: copy_map(&self.dispute_clients),
+            tx_clients: copy_map(&self.tx_clients),
+            by_currency: self
+                .by_currency
+                .iter()
+                .map(|(currency, engine)| (currency.clone(), engine.state()))
+                .collect(),
+            tx_currencies: self
+                .tx_currencies
+                .iter()
+                .map(|(tx, currency)| (*tx, currency.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn save_state<W: Write>(&self, out: W) -> serde_json::Result<()>
+    where
+        A: Serialize,
+    {
+        serde_json::to_writer(out, &self.state())
+    }
+
+    /// Folds in an engine that processed another partition of the input, e.g.
+    /// in parallel. Clients present in both have their balances summed and
+    /// are locked if either shard locked them (see `Account::merge`); dispute
+    /// bookkeeping, transactions held by `defer_unresolved`, history, alerts,
+    /// warnings and errors are combined. Transaction ids are assumed unique
+    /// across shards, with `other` winning on a clash.
+    pub fn merge(&mut self, other: Self) {
+        for client in other.order {
+            if let Some(account) = other.accounts.get(&client) {
+                match self.accounts.get_mut(&client) {
+                    Some(existing) => existing.merge(account),
                     None => {
-                        tx_errors.push(format!(
-                            "Could not find applied transaction \"{}\" to dispute",
-                            transaction.tx
-                        ));
-                        continue;
+                        self.order.push(client);
+                        self.accounts.insert(client, account.clone());
                     }
-                };
+                }
+            }
+        }
+        self.applied_txs.extend(other.applied_txs);
+        self.disputed_txs.extend(other.disputed_txs);
+        self.dispute_clients.extend(other.dispute_clients);
+        self.tx_clients.extend(other.tx_clients);
+        self.resolved_txs.extend(other.resolved_txs);
+        self.withdrawal_txs.extend(other.withdrawal_txs);
+        self.charged_back_txs.extend(other.charged_back_txs);
+        for (client, transactions) in other.history {
+            self.history.entry(client).or_default().extend(transactions);
+        }
+        self.alerts.extend(other.alerts);
+        self.warnings.extend(other.warnings);
+        self.trace.extend(other.trace);
+        self.tx_errors.extend(other.tx_errors);
+        for (client, errors) in other.client_errors {
+            self.client_errors.entry(client).or_default().extend(errors);
+        }
+        self.movements.extend(other.movements);
+        for (currency, engine) in other.by_currency {
+            match self.by_currency.get_mut(&currency) {
+                Some(existing) => existing.merge(engine),
+                None => {
+                    self.by_currency.insert(currency, engine);
+                }
+            }
+        }
+        self.tx_currencies.extend(other.tx_currencies);
+        self.applied_count += other.applied_count;
+        self.deferred.extend(other.deferred);
+        self.deferred_txs.extend(other.deferred_txs);
+    }
 
-                match disputed_txs.get(&transaction.tx) {
-                    Some(_) => {
-                        tx_errors.push(format!(
-                            "Could not dispute same transaction \"{}\" twice",
-                            transaction.tx
-                        ));
-                        continue;
-                    }
-                    None => {}
-                };
+    /// Applies a single transaction. A rejected transaction leaves balances
+    /// untouched; its error is returned and also collected for `finish`.
+    pub fn apply(&mut self, mut transaction: Transaction) -> Result<(), EngineError<A>> {
+        if let Some(canonical) = self.config.aliases.get(&transaction.transaction_type) {
+            transaction.transaction_type = canonical.clone();
+        }
+        if let Some(mut hook) = self.before_apply.take() {
+            hook(self, &transaction);
+            self.before_apply = Some(hook);
+        }
+        self.applied_count += 1;
+        let currency = transaction
+            .currency
+            .clone()
+            .or_else(|| self.tx_currencies.get(&transaction.tx).cloned());
+        if let Some(currency) = currency.filter(|currency| Some(currency) != self.currency.as_ref())
+        {
+            return self.apply_in_currency(currency, transaction);
+        }
+        let before: Vec<Account<A>> = if self.config.record_trace {
+            std::iter::once(transaction.client)
+                .chain(transaction.destination)
+                .map(|client| {
+                    self.accounts
+                        .get(&client)
+                        .cloned()
+                        .unwrap_or_else(|| Account::empty(client))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let referential = matches!(
+            transaction.transaction_type.as_str(),
+            "dispute" | "resolve" | "chargeback" | "chargeback_reversal"
+        );
+        if self.config.defer_unresolved
+            && referential
+            && self.deferred_txs.contains(&transaction.tx)
+        {
+            self.defer(transaction);
+            return Ok(());
+        }
+        let totals_before: Vec<(u16, A)> = std::iter::once(transaction.client)
+            .chain(transaction.destination)
+            .map(|client| {
+                (
+                    client,
+                    self.accounts.get(&client).map_or(A::zero(), |a| a.total),
+                )
+            })
+            .collect();
+        let result = self.process(&transaction);
+        let unresolved = matches!(
+            result,
+            Err(EngineError::UnknownTransaction { .. }) | Err(EngineError::ReferentialOnly { .. })
+        );
+        if self.config.defer_unresolved && unresolved && transaction.transaction_type == "dispute" {
+            self.defer(transaction);
+            return Ok(());
+        }
+        for before in before {
+            let after = self
+                .accounts
+                .get(&before.client)
+                .cloned()
+                .unwrap_or_else(|| Account::empty(before.client));
+            self.trace.push(format!(
+                "tx {} {} client {}: available {} -> {}, held {} -> {}, total {} -> {}, locked {} -> {}{}",
+                transaction.tx,
+                transaction.transaction_type,
+                before.client,
+                before.available,
+                after.available,
+                before.held,
+                after.held,
+                before.total,
+                after.total,
+                before.locked,
+                after.locked,
+                if result.is_ok() { "" } else { " (rejected)" }
+            ));
+        }
+        if result.is_ok() {
+            for (client, total_before) in totals_before {
+                let total = self.accounts.get(&client).map_or(A::zero(), |a| a.total);
+                if total < A::zero() && total_before >= A::zero() {
+                    self.warnings.push(format!(
+                        "Warning: total of client {} went negative ({}) after transaction \"{}\"",
+                        client, total, transaction.tx
+                    ));
+                }
+            }
+            let index = self.applied_count;
+            let touched = std::iter::once(transaction.client).chain(transaction.destination);
+            for client in touched {
+                if let Some(account) = self.accounts.get_mut(&client) {
+                    account.last_modified = Some(index);
+                }
+            }
+        }
+        if result.is_ok() && self.config.track_undisputed {
+            self.track(&transaction);
+        }
+        if result.is_ok() && self.config.normalize_balances {
+            self.normalize(transaction.client);
+            if let Some(destination) = transaction.destination {
+                self.normalize(destination);
+            }
+        }
+        match &result {
+            Ok(_) if self.config.record_history => {
+                if let Some(destination) = transaction.destination {
+                    self.history
+                        .entry(destination)
+                        .or_default()
+                        .push(transaction.clone());
+                }
+                self.history
+                    .entry(transaction.client)
+                    .or_default()
+                    .push(transaction);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                self.tx_errors.push(err.to_string());
+                self.client_errors
+                    .entry(transaction.client)
+                    .or_default()
+                    .push(err.clone());
+            }
+        }
+        result
+    }
+
+    /// Like `apply`, also passing the transaction and its outcome to
+    /// `callback`, e.g. for metrics or logging without parsing the error
+    /// strings. A transaction held back by `defer_unresolved` reports `Ok`.
+    pub fn apply_with<F>(
+        &mut self,
+        transaction: Transaction,
+        callback: F,
+    ) -> Result<(), EngineError<A>>
+    where
+        F: FnOnce(&Transaction, Result<(), EngineError<A>>),
+    {
+        let result = self.apply(transaction.clone());
+        callback(&transaction, result.clone());
+        result
+    }
+
+    /// Applies `txs` all or nothing: once one is rejected, the effects of the
+    /// block's earlier transactions are undone, leaving the engine as it was
+    /// before the block, and that rejection is returned (and collected for
+    /// `finish`). Only what the block can touch is saved beforehand, so
+    /// blocks stay cheap on a large engine. A transaction held back by
+    /// `defer_unresolved` counts as applied.
+    pub fn transaction_block(&mut self, txs: &[Transaction]) -> Result<(), EngineError<A>> {
+        let checkpoint = Checkpoint::save(self, txs);
+        for transaction in txs {
+            if let Err(err) = self.apply(transaction.clone()) {
+                checkpoint.restore(self);
+                self.tx_errors.push(err.to_string());
+                self.client_errors
+                    .entry(transaction.client)
+                    .or_default()
+                    .push(err.clone());
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `hook` with the current engine state before every transaction is
+    /// applied, e.g. to check intermediate balances in ordering tests.
+    pub fn set_before_apply<F>(&mut self, hook: F)
+    where
+        F: FnMut(&Engine<A>, &Transaction) + 'static,
+    {
+        self.before_apply = Some(Box::new(hook));
+    }
+
+    /// Applies the `Ok` items in order and records `Err` items, e.g. rows
+    /// that failed to parse, among the transaction errors. Returns the
+    /// errors of this call, parse and engine errors interleaved in input
+    /// order.
+    pub fn apply_all<E: fmt::Display>(
+        &mut self,
+        transactions: impl IntoIterator<Item = Result<Transaction, E>>,
+    ) -> Vec<String> {
+        let start = self.tx_errors.len();
+        for transaction in transactions {
+            match transaction {
+                Ok(transaction) => {
+                    let _ = self.apply(transaction);
+                }
+                Err(err) => self.tx_errors.push(err.to_string()),
+            }
+        }
+        self.tx_errors[start..].to_vec()
+    }
+
+    /// Amount currently held for a disputed transaction.
+    pub fn disputed_amount(&self, tx: u32) -> Option<A> {
+        self.disputed_txs.get(&tx).copied()
+    }
+
+    /// Transactions under dispute with the amount held for each, in no
+    /// particular order.
+    pub fn disputed(&self) -> impl Iterator<Item = (u32, A)> + '_ {
+        self.disputed_txs.iter().map(|(tx, amount)| (*tx, *amount))
+    }
+
+    /// The open disputes making up `account`'s held balance, as transaction
+    /// id and amount held, by id.
+    pub fn held_breakdown(&self, account: &Account<A>) -> Vec<(u32, A)> {
+        let engine = match &account.currency {
+            Some(currency) if Some(currency) != self.currency.as_ref() => {
+                match self.by_currency.get(currency) {
+                    Some(engine) => engine,
+                    None => return Vec::new(),
+                }
+            }
+            _ => self,
+        };
+        let mut held: Vec<(u32, A)> = engine
+            .disputed_txs
+            .iter()
+            .filter(|(tx, _)| engine.dispute_clients.get(tx) == Some(&account.client))
+            .map(|(tx, amount)| (*tx, *amount))
+            .collect();
+        held.sort_unstable_by_key(|(tx, _)| *tx);
+        held
+    }
+
+    /// Ids of the applied deposits and withdrawals, in no particular order.
+    pub fn applied(&self) -> impl Iterator<Item = u32> + '_ {
+        self.applied_txs.keys().copied()
+    }
+
+    /// Current state of a client's account, if it has been seen.
+    pub fn account(&self, client: u16) -> Option<&Account<A>> {
+        self.accounts.get(&client)
+    }
+
+    /// Per-client transaction history, empty unless `record_history` is set.
+    pub fn history(&self) -> &BTreeMap<u16, Vec<Transaction>> {
+        &self.history
+    }
+
+    /// Rejected transactions so far by client id, each client's in input
+    /// order. Unlike the errors `finish` returns, rows that failed to parse
+    /// are left out, having no client to report.
+    pub fn errors_by_client(&self) -> HashMap<u16, Vec<EngineError<A>>> {
+        self.client_errors.clone()
+    }
+
+    /// Monitoring alerts raised so far, such as large deposits.
+    pub fn alerts(&self) -> &[String] {
+        &self.alerts
+    }
+
+    /// Warnings about applied transactions so far, such as a total going
+    /// negative. Unlike rejections they aren't among the errors `finish`
+    /// returns.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// One line per transaction and touched account with its balances before
+    /// and after, in input order; empty unless `record_trace` is set.
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    /// The `n` largest deposits and withdrawals that were never disputed,
+    /// largest first. Empty unless `track_undisputed` is set.
+    pub fn largest_undisputed(&self, n: usize) -> Vec<&Movement<A>> {
+        let mut movements: Vec<&Movement<A>> = self
+            .movements
+            .values()
+            .filter(|movement| !movement.disputed)
+            .collect();
+        movements.sort_by(|a, b| b.amount.cmp(&a.amount).then(a.tx.cmp(&b.tx)));
+        movements.truncate(n);
+        movements
+    }
+
+    pub fn history_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.history)
+    }
+
+    pub fn finish(mut self) -> (Vec<Account<A>>, Vec<String>) {
+        self.retry_deferred();
+        let errors = std::mem::take(&mut self.tx_errors);
+        (self.into_accounts().collect(), errors)
+    }
+
+    /// Consumes the engine, yielding the final accounts in the configured
+    /// order one at a time, e.g. to write them out without collecting them.
+    /// Accounts of transactions with a currency follow, grouped by currency.
+    pub fn into_accounts(mut self) -> impl Iterator<Item = Account<A>> {
+        self.retry_deferred();
+        let mut accounts = self.accounts;
+        let mut order = self.order;
+        if self.config.order == OutputOrder::ByClientId {
+            order.sort_unstable();
+        }
+        let currency = self.currency;
+        let in_currencies: Vec<Account<A>> = self
+            .by_currency
+            .into_values()
+            .flat_map(Self::into_accounts)
+            .collect();
+        order
+            .into_iter()
+            .filter_map(move |client| accounts.remove(&client))
+            .map(move |mut account| {
+                account.currency = currency.clone();
+                account
+            })
+            .chain(in_currencies)
+    }
+
+    /// Current accounts in the configured order, as `into_accounts` yields
+    /// them but without retrying deferred transactions, e.g. to snapshot an
+    /// engine that keeps running.
+    pub fn accounts(&self) -> Vec<Account<A>> {
+        let mut order = self.order.clone();
+        if self.config.order == OutputOrder::ByClientId {
+            order.sort_unstable();
+        }
+        order
+            .iter()
+            .filter_map(|client| self.accounts.get(client))
+            .map(|account| Account {
+                currency: self.currency.clone(),
+                ..account.clone()
+            })
+            .chain(self.by_currency.values().flat_map(Self::accounts))
+            .collect()
+    }
+
+    fn defer(&mut self, transaction: Transaction) {
+        self.deferred_txs.insert(transaction.tx);
+        self.deferred.push((self.applied_count, transaction));
+    }
 
-                match account.dispute(disputable) {
-                    Ok(_) => disputed_txs.insert(transaction.tx, disputable),
-                    Err(err) => {
-                        tx_errors.push(format!(
-                            "Could not dispute transaction \"{}\": {}",
-                            transaction.tx, err
+    /// Applies the transactions held by `defer_unresolved` in input order,
+    /// now rejecting those still referencing unknown transactions. `finish`
+    /// does so too; call it first to inspect the engine with them applied.
+    pub fn retry_deferred(&mut self) {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        let mut client_errors = Vec::new();
+        for engine in self.by_currency.values_mut() {
+            engine.retry_deferred();
+            errors.append(&mut engine.tx_errors);
+            warnings.append(&mut engine.warnings);
+            client_errors.extend(engine.client_errors.drain());
+        }
+        self.tx_errors.append(&mut errors);
+        self.warnings.append(&mut warnings);
+        for (client, mut rejected) in client_errors {
+            self.client_errors
+                .entry(client)
+                .or_default()
+                .append(&mut rejected);
+        }
+
+        let applied_count = self.applied_count;
+        let defer_unresolved = std::mem::replace(&mut self.config.defer_unresolved, false);
+        self.deferred_txs.clear();
+        for (index, transaction) in std::mem::take(&mut self.deferred) {
+            self.applied_count = index - 1;
+            let _ = self.apply(transaction);
+        }
+        self.applied_count = applied_count;
+        self.config.defer_unresolved = defer_unresolved;
+    }
+
+    /// Applies a transaction to the accounts of its currency, keeping errors,
+    /// alerts, warnings and the trace in input order.
+    fn apply_in_currency(
+        &mut self,
+        currency: String,
+        transaction: Transaction,
+    ) -> Result<(), EngineError<A>> {
+        let tx = transaction.tx;
+        let movement = matches!(
+            transaction.transaction_type.as_str(),
+            "deposit" | "withdrawal"
+        );
+        let applied_count = self.applied_count;
+        let other_accounts = self.accounts.len()
+            + self
+                .by_currency
+                .iter()
+                .filter(|(other, _)| **other != currency)
+                .map(|(_, engine)| engine.accounts.len())
+                .sum::<usize>();
+        let engine = self.currency_engine(currency.clone());
+        engine.applied_count = applied_count - 1;
+        engine.other_accounts = other_accounts;
+        let result = engine.apply(transaction);
+        let mut errors = std::mem::take(&mut engine.tx_errors);
+        let mut alerts = std::mem::take(&mut engine.alerts);
+        let mut warnings = std::mem::take(&mut engine.warnings);
+        let mut trace = std::mem::take(&mut engine.trace);
+        let client_errors = std::mem::take(&mut engine.client_errors);
+        self.tx_errors.append(&mut errors);
+        for (client, mut rejected) in client_errors {
+            self.client_errors
+                .entry(client)
+                .or_default()
+                .append(&mut rejected);
+        }
+        self.alerts.append(&mut alerts);
+        self.warnings.append(&mut warnings);
+        self.trace.append(&mut trace);
+        if result.is_ok() && movement {
+            self.tx_currencies.insert(tx, currency);
+        }
+        result
+    }
+
+    fn currency_engine(&mut self, currency: String) -> &mut Self {
+        let config = &self.config;
+        self.by_currency.entry(currency.clone()).or_insert_with(|| {
+            let mut engine = Self::with_config(EngineConfig {
+                // Already mapped by this engine.
+                aliases: HashMap::new(),
+                ..config.clone()
+            });
+            engine.currency = Some(currency);
+            engine
+        })
+    }
+
+    fn track(&mut self, transaction: &Transaction) {
+        match transaction.transaction_type.as_str() {
+            "deposit" | "withdrawal" => {
+                if let Some(&amount) = self.applied_txs.get(&transaction.tx) {
+                    self.movements.insert(
+                        transaction.tx,
+                        Movement {
+                            tx: transaction.tx,
+                            client: transaction.client,
+                            transaction_type: transaction.transaction_type.clone(),
+                            amount,
+                            disputed: false,
+                        },
+                    );
+                }
+            }
+            "dispute" => {
+                if let Some(movement) = self.movements.get_mut(&transaction.tx) {
+                    movement.disputed = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// `overdraft` in the engine's `Amount` type; one the type can't hold
+    /// allows none.
+    fn overdraft(&self) -> A {
+        A::from_decimal(self.config.overdraft, self.config.precision).unwrap_or_else(A::zero)
+    }
+
+    fn normalize(&mut self, client: u16) {
+        let precision = self.config.precision;
+        if let Some(account) = self.accounts.get_mut(&client) {
+            account.normalize(precision);
+            debug_assert!(
+                account.total.round(precision) == account.total,
+                "client {} balance exceeds {} decimal places",
+                client,
+                precision
+            );
+        }
+    }
+
+    /// Checks a transaction on its own, without looking at or touching any
+    /// account: its type is known, it doesn't involve client 0 under
+    /// `reserve_client_zero`, it has the amount (and destination) it needs,
+    /// and amounts are neither negative where funds move nor above
+    /// `MAX_AMOUNT`. `apply` runs the same checks first; call it to lint a
+    /// batch up front.
+    pub fn validate(&self, transaction: &Transaction) -> Result<(), EngineError<A>> {
+        match self.config.aliases.get(&transaction.transaction_type) {
+            Some(canonical) => self.check(&Transaction {
+                transaction_type: canonical.clone(),
+                ..transaction.clone()
+            }),
+            None => self.check(transaction),
+        }
+    }
+
+    /// `validate` for a transaction whose type was already mapped through
+    /// `aliases`.
+    fn check(&self, transaction: &Transaction) -> Result<(), EngineError<A>> {
+        let tx = transaction.tx;
+        let transaction_type = transaction.transaction_type.as_str();
+        if !matches!(
+            transaction_type,
+            "deposit"
+                | "withdrawal"
+                | "dispute"
+                | "resolve"
+                | "chargeback"
+                | "chargeback_reversal"
+                | "transfer"
+        ) {
+            return Err(EngineError::UnhandledType(transaction_type.to_string()));
+        }
+        if self.config.reserve_client_zero
+            && (transaction.client == 0 || transaction.destination == Some(0))
+        {
+            return Err(EngineError::ReservedClient { tx });
+        }
+        let amount = transaction
+            .amount
+            .map(|amount| round_amount(amount, self.config.precision));
+        // Types say which way funds move, so amounts are magnitudes; a
+        // negative withdrawal would otherwise credit the account.
+        let moves_funds = matches!(transaction_type, "deposit" | "withdrawal" | "transfer")
+            || (transaction_type == "chargeback" && self.config.partial_chargebacks);
+        if let Some(amount) = amount.filter(|amount| moves_funds && *amount < Decimal::ZERO) {
+            return Err(EngineError::NegativeAmount {
+                transaction_type: transaction_type.to_string(),
+                tx,
+                amount: amount.normalize(),
+            });
+        }
+        if let Some(amount) = amount.filter(|amount| amount.abs() > MAX_AMOUNT) {
+            return Err(EngineError::AmountOutOfRange {
+                transaction_type: transaction_type.to_string(),
+                tx,
+                amount: amount.normalize(),
+            });
+        }
+        match transaction_type {
+            "deposit" | "withdrawal" if amount.is_none() => Err(EngineError::MissingAmount {
+                transaction_type: transaction_type.to_string(),
+                tx,
+            }),
+            "transfer" if amount.is_none() || transaction.destination.is_none() => {
+                Err(EngineError::IncompleteTransfer { tx })
+            }
+            "transfer" if transaction.destination == Some(transaction.client) => {
+                Err(EngineError::SelfTransfer { tx })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn process(&mut self, transaction: &Transaction) -> Result<(), EngineError<A>> {
+        self.check(transaction)?;
+        let tx = transaction.tx;
+        let precision = self.config.precision;
+        let overdraft = self.overdraft();
+        let amount = transaction
+            .amount
+            .map(|amount| {
+                A::from_decimal(amount, precision).ok_or_else(|| EngineError::AmountOverflow {
+                    transaction_type: transaction.transaction_type.clone(),
+                    tx,
+                    amount,
+                })
+            })
+            .transpose()?;
+        // Disputes, resolves and chargebacks only reference earlier
+        // transactions, so they must not open an account on their own.
+        let referential = matches!(
+            transaction.transaction_type.as_str(),
+            "dispute" | "resolve" | "chargeback" | "chargeback_reversal"
+        );
+        if referential && !self.accounts.contains_key(&transaction.client) {
+            return Err(EngineError::ReferentialOnly {
+                client: transaction.client,
+            });
+        }
+        let transaction_type = transaction.transaction_type.as_str();
+        if matches!(transaction_type, "deposit" | "withdrawal")
+            && self.applied_txs.contains_key(&tx)
+        {
+            let original_type = if self.withdrawal_txs.contains(&tx) {
+                "withdrawal"
+            } else {
+                "deposit"
+            };
+            let transaction_type = transaction_type.to_string();
+            return Err(if transaction_type == original_type {
+                EngineError::DuplicateTransaction {
+                    tx,
+                    transaction_type,
+                }
+            } else {
+                EngineError::TransactionTypeConflict {
+                    tx,
+                    transaction_type,
+                    original_type,
+                }
+            });
+        }
+        if let Some(max) = self.config.max_clients {
+            // Transfer destinations count too, so a transfer is rejected as a
+            // whole.
+            let mut new_clients: Vec<u16> = std::iter::once(transaction.client)
+                .chain(
+                    transaction
+                        .destination
+                        .filter(|_| transaction_type == "transfer"),
+                )
+                .filter(|client| !self.accounts.contains_key(client))
+                .collect();
+            new_clients.dedup();
+            let accounts = self.accounts.len()
+                + self.other_accounts
+                + self
+                    .by_currency
+                    .values()
+                    .map(|engine| engine.accounts.len())
+                    .sum::<usize>();
+            if let Some(&client) = new_clients.first() {
+                if accounts + new_clients.len() > max {
+                    return Err(EngineError::TooManyClients { client, max });
+                }
+            }
+        }
+        let order = &mut self.order;
+        let account = self.accounts.entry(transaction.client).or_insert_with(|| {
+            order.push(transaction.client);
+            Account::empty(transaction.client)
+        });
+        account.first_tx.get_or_insert(tx);
+
+        match transaction.transaction_type.as_str() {
+            "deposit" => {
+                let amount = amount.ok_or_else(|| EngineError::MissingAmount {
+                    transaction_type: transaction.transaction_type.clone(),
+                    tx,
+                })?;
+                account.deposit(amount).unwrap();
+                self.applied_txs.insert(tx, amount);
+                self.tx_clients.insert(tx, transaction.client);
+                let threshold = self
+                    .config
+                    .large_deposit_threshold
+                    .and_then(|threshold| A::from_decimal(threshold, precision));
+                if let Some(threshold) = threshold {
+                    if amount > threshold {
+                        self.alerts.push(format!(
+                            "large deposit: client {} tx {} amount {}",
+                            transaction.client, tx, amount
                         ));
-                        continue;
                     }
+                }
+            }
+            "withdrawal" => {
+                let amount = amount.ok_or_else(|| EngineError::MissingAmount {
+                    transaction_type: transaction.transaction_type.clone(),
+                    tx,
+                })?;
+                account
+                    .withdraw_with_overdraft(amount, overdraft)
+                    .map_err(|reason| EngineError::WithdrawalRejected { tx, reason })?;
+                self.applied_txs.insert(tx, amount);
+                self.tx_clients.insert(tx, transaction.client);
+                self.withdrawal_txs.insert(tx);
+            }
+            "dispute" => {
+                let disputable = match self.applied_txs.get(&tx) {
+                    Some(disputable) => *disputable,
+                    None => return Err(EngineError::UnknownTransaction { tx }),
                 };
+                // States saved before owners were tracked have none to check.
+                if matches!(self.tx_clients.get(&tx), Some(owner) if *owner != transaction.client) {
+                    return Err(EngineError::ForeignTransaction {
+                        client: transaction.client,
+                        tx,
+                    });
+                }
+
+                if self.disputed_txs.contains_key(&tx) {
+                    return Err(EngineError::AlreadyDisputed { tx });
+                }
+
+                if !self.config.allow_redispute && self.resolved_txs.contains(&tx) {
+                    return Err(EngineError::AlreadyResolved { tx });
+                }
+
+                // Chargebacks deduct from the applied amount, so cycling
+                // disputes can never hold more than is left of the original.
+                if disputable == A::zero() {
+                    return Err(EngineError::FullyChargedBack { tx });
+                }
+
+                // The account is frozen pending the earlier chargeback.
+                if account.locked {
+                    return Err(EngineError::DisputeOnLockedAccount {
+                        client: transaction.client,
+                    });
+                }
+
+                // Only withdrawals and outgoing transfers lower available funds
+                // below an undisputed amount, so it has been spent.
+                let policy = self.config.dispute_policy;
+                if policy == DisputePolicy::StrictAvailable && disputable > account.available {
+                    return Err(EngineError::CannotDisputeSpentFunds {
+                        tx,
+                        amount: disputable,
+                        available: account.available,
+                    });
+                }
+
+                account
+                    .dispute(disputable, policy)
+                    .map_err(|reason| EngineError::DisputeRejected { tx, reason })?;
+                self.disputed_txs.insert(tx, disputable);
+                self.dispute_clients.insert(tx, transaction.client);
             }
             "resolve" => {
-                let resolvable = match disputed_txs.get(&transaction.tx) {
-                    Some(amount) => amount.clone(),
+                let resolvable = match self.disputed_txs.get(&tx) {
+                    Some(amount) => *amount,
                     None => {
-                        tx_errors.push(format!(
-                            "Could not find disputed transaction \"{}\" to resolve",
-                            transaction.tx
-                        ));
-                        continue;
+                        return Err(EngineError::NotDisputed {
+                            tx,
+                            action: "resolve",
+                        })
                     }
                 };
+                // Like disputes, states saved before owners were tracked have
+                // none to check.
+                if matches!(self.dispute_clients.get(&tx), Some(owner) if *owner != transaction.client)
+                {
+                    return Err(EngineError::ForeignTransaction {
+                        client: transaction.client,
+                        tx,
+                    });
+                }
+                verify_amount(&self.config, transaction, amount, resolvable)?;
 
-                match account.resolve(resolvable) {
-                    Ok(_) => disputed_txs.remove(&transaction.tx),
-                    Err(err) => {
-                        tx_errors.push(format!(
-                            "Could not resolve disputed transaction \"{}\": {}",
-                            transaction.tx, err
-                        ));
-                        continue;
-                    }
-                };
+                let client = transaction.client;
+                let locked_by_other = account.locked
+                    && self
+                        .charged_back_txs
+                        .iter()
+                        .any(|(id, (owner, _))| *owner == client && *id != tx);
+                if locked_by_other && !self.config.allow_locked_resolve {
+                    return Err(EngineError::ResolveOnLockedAccount { client, tx });
+                }
+
+                account
+                    .resolve(resolvable)
+                    .map_err(|reason| EngineError::ResolveRejected { tx, reason })?;
+                assert_held_non_negative(account, tx);
+                self.disputed_txs.remove(&tx);
+                self.dispute_clients.remove(&tx);
+                self.resolved_txs.insert(tx);
             }
             "chargeback" => {
-                let back_chargeable = match disputed_txs.get(&transaction.tx) {
-                    Some(amount) => amount.clone(),
+                let back_chargeable = match self.disputed_txs.get(&tx) {
+                    Some(amount) => *amount,
                     None => {
-                        tx_errors.push(format!(
-                            "Could not find disputed transaction \"{}\" to charge back",
-                            transaction.tx
-                        ));
-                        continue;
+                        return Err(EngineError::NotDisputed {
+                            tx,
+                            action: "charge back",
+                        })
                     }
                 };
-
-                match account.chargeback(back_chargeable) {
-                    Ok(_) => disputed_txs.remove(&transaction.tx),
-                    Err(err) => {
-                        tx_errors.push(format!(
-                            "Could not charge back disputed transaction \"{}\": {}",
-                            transaction.tx, err
-                        ));
-                        continue;
+                // Like disputes, states saved before owners were tracked have
+                // none to check.
+                if matches!(self.dispute_clients.get(&tx), Some(owner) if *owner != transaction.client)
+                {
+                    return Err(EngineError::ForeignTransaction {
+                        client: transaction.client,
+                        tx,
+                    });
+                }
+                // `disputed_txs` keeps what is still held for the transaction,
+                // so a resolve after a partial chargeback only releases that.
+                let partial = match amount {
+                    Some(amount) if self.config.partial_chargebacks => {
+                        if amount > back_chargeable {
+                            return Err(EngineError::ChargebackRejected {
+                                tx,
+                                reason: format!(
+                                    "amount {} exceeds the {} still held",
+                                    amount, back_chargeable
+                                ),
+                            });
+                        }
+                        Some(amount)
+                    }
+                    _ => {
+                        verify_amount(&self.config, transaction, amount, back_chargeable)?;
+                        None
                     }
                 };
+                let charged_back = partial.unwrap_or(back_chargeable);
+
+                account
+                    .chargeback(charged_back)
+                    .map_err(|reason| EngineError::ChargebackRejected { tx, reason })?;
+                assert_held_non_negative(account, tx);
+                if charged_back < back_chargeable {
+                    self.disputed_txs.insert(tx, back_chargeable - charged_back);
+                } else {
+                    self.disputed_txs.remove(&tx);
+                    self.dispute_clients.remove(&tx);
+                }
+                if let Some(applied) = self.applied_txs.get_mut(&tx) {
+                    *applied -= charged_back;
+                }
+                self.charged_back_txs
+                    .entry(tx)
+                    .or_insert((transaction.client, A::zero()))
+                    .1 += charged_back;
+                // The first chargeback froze the account, so it stays the reason.
+                account.lock_reason.get_or_insert_with(|| lock_reason(tx));
+            }
+            "chargeback_reversal" => {
+                let reversible = match self.charged_back_txs.get(&tx) {
+                    Some(&(client, amount)) if client == transaction.client => amount,
+                    _ => return Err(EngineError::NotChargedBack { tx }),
+                };
+                self.charged_back_txs.remove(&tx);
+
+                account.reverse_chargeback(reversible);
+                let client = transaction.client;
+                let still_charged_back = self
+                    .charged_back_txs
+                    .iter()
+                    .filter(|(_, (c, _))| *c == client)
+                    .map(|(id, _)| *id)
+                    .min();
+                match still_charged_back {
+                    Some(other) => account.lock_reason = Some(lock_reason(other)),
+                    None => {
+                        account.locked = false;
+                        account.lock_reason = None;
+                    }
+                }
+                if let Some(applied) = self.applied_txs.get_mut(&tx) {
+                    *applied += reversible;
+                }
+                // A partial chargeback may have left part of the dispute open.
+                if !self.disputed_txs.contains_key(&tx) {
+                    self.resolved_txs.insert(tx);
+                }
             }
-            t => {
-                tx_errors.push(format!("Unhandled transaction type: \"{}\"", t));
+            "transfer" => {
+                let (amount, destination) = match (amount, transaction.destination) {
+                    (Some(amount), Some(destination)) => (amount, destination),
+                    _ => return Err(EngineError::IncompleteTransfer { tx }),
+                };
+
+                // Only the source can fail, so checking it first keeps the
+                // transfer atomic.
+                account
+                    .withdraw_with_overdraft(amount, overdraft)
+                    .map_err(|reason| EngineError::TransferRejected { tx, reason })?;
+                let order = &mut self.order;
+                let destination_account = self.accounts.entry(destination).or_insert_with(|| {
+                    order.push(destination);
+                    Account::empty(destination)
+                });
+                destination_account.first_tx.get_or_insert(tx);
+                destination_account.deposit(amount).unwrap();
             }
+            t => return Err(EngineError::UnhandledType(t.to_string())),
         };
+
+        Ok(())
+    }
+}
+
+/// Disputes share a single held balance, so releasing more than was held for
+/// a transaction means the dispute bookkeeping is out of sync with balances.
+fn assert_held_non_negative<A: Amount>(account: &Account<A>, tx: u32) {
+    assert!(
+        account.held >= A::zero(),
+        "client {} held funds went negative ({}) releasing tx {}",
+        account.client,
+        account.held,
+        tx
+    );
+}
+
+fn verify_amount<A: Amount>(
+    config: &EngineConfig,
+    transaction: &Transaction,
+    amount: Option<A>,
+    disputed: A,
+) -> Result<(), EngineError<A>> {
+    match amount {
+        Some(amount) if config.verify_amounts && amount != disputed => {
+            Err(EngineError::AmountMismatch {
+                tx: transaction.tx,
+                transaction_type: transaction.transaction_type.clone(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn process_transactions(transactions: Vec<Transaction>) -> (Vec<Account>, Vec<String>) {
+    process_transactions_from(Vec::new(), transactions, &EngineConfig::default())
+}
+
+/// Applies `transactions` to caller-owned accounts and dispute bookkeeping in
+/// place, returning the errors of the rejected ones. Calling it again with the
+/// same maps continues where the previous batch left off. The accounts hold a
+/// single balance per client, so transactions with a currency are rejected;
+/// use an `Engine` for those.
+pub fn process_transactions_with_state(
+    transactions: Vec<Transaction>,
+    accounts: &mut HashMap<u16, Account>,
+    ledger: &mut Ledger,
+) -> Vec<EngineError> {
+    let mut engine = Engine::new(EngineConfig::default());
+    engine.order = accounts.keys().copied().collect();
+    engine.accounts = std::mem::take(accounts).into_iter().collect();
+    engine.applied_txs = std::mem::take(&mut ledger.applied_txs)
+        .into_iter()
+        .collect();
+    engine.disputed_txs = std::mem::take(&mut ledger.disputed_txs)
+        .into_iter()
+        .collect();
+    engine.resolved_txs = std::mem::take(&mut ledger.resolved_txs);
+    engine.withdrawal_txs = std::mem::take(&mut ledger.withdrawal_txs);
+    engine.charged_back_txs = std::mem::take(&mut ledger.charged_back_txs);
+    engine.dispute_clients = std::mem::take(&mut ledger.dispute_clients)
+        .into_iter()
+        .collect();
+    engine.tx_clients = std::mem::take(&mut ledger.tx_clients).into_iter().collect();
+
+    let errors = transactions
+        .into_iter()
+        .filter_map(|transaction| match transaction.currency {
+            Some(_) => Some(EngineError::CurrencyWithoutEngine { tx: transaction.tx }),
+            None => engine.apply(transaction).err(),
+        })
+        .collect();
+
+    accounts.extend(engine.accounts);
+    ledger.applied_txs.extend(engine.applied_txs);
+    ledger.disputed_txs.extend(engine.disputed_txs);
+    ledger.resolved_txs = engine.resolved_txs;
+    ledger.withdrawal_txs = engine.withdrawal_txs;
+    ledger.charged_back_txs = engine.charged_back_txs;
+    ledger.dispute_clients.extend(engine.dispute_clients);
+    ledger.tx_clients.extend(engine.tx_clients);
+    errors
+}
+
+fn lock_reason(tx: u32) -> String {
+    format!("chargeback of tx {}", tx)
+}
+
+/// What a block of transactions can change in an engine, saved by
+/// `Engine::transaction_block` to undo the block: the accounts of its
+/// clients, the bookkeeping of its transaction ids and the lengths of
+/// everything transactions append to.
+struct Checkpoint<A: Amount> {
+    accounts: Vec<(u16, Option<Account<A>>)>,
+    history: Vec<(u16, Option<usize>)>,
+    client_errors: Vec<(u16, Option<usize>)>,
+    txs: Vec<TxCheckpoint<A>>,
+    order: usize,
+    alerts: usize,
+    warnings: usize,
+    trace: usize,
+    tx_errors: usize,
+    applied_count: u64,
+    deferred: usize,
+    /// Checkpoints of the currency engines that existed; those created by
+    /// the block are dropped on restore.
+    by_currency: Vec<(String, Checkpoint<A>)>,
+}
+
+/// The bookkeeping of one transaction id, see `Checkpoint`.
+struct TxCheckpoint<A: Amount> {
+    tx: u32,
+    applied: Option<A>,
+    tx_client: Option<u16>,
+    disputed: Option<A>,
+    dispute_client: Option<u16>,
+    resolved: bool,
+    withdrawal: bool,
+    charged_back: Option<(u16, A)>,
+    currency: Option<String>,
+    movement: Option<Movement<A>>,
+    deferred: bool,
+}
+
+impl<A: Amount> Checkpoint<A> {
+    fn save(engine: &Engine<A>, txs: &[Transaction]) -> Self {
+        let clients: BTreeSet<u16> = txs
+            .iter()
+            .flat_map(|transaction| {
+                std::iter::once(transaction.client).chain(transaction.destination)
+            })
+            .collect();
+        let ids: BTreeSet<u32> = txs.iter().map(|transaction| transaction.tx).collect();
+        Self {
+            accounts: clients
+                .iter()
+                .map(|client| (*client, engine.accounts.get(client).cloned()))
+                .collect(),
+            history: clients
+                .iter()
+                .map(|client| (*client, engine.history.get(client).map(Vec::len)))
+                .collect(),
+            client_errors: clients
+                .iter()
+                .map(|client| (*client, engine.client_errors.get(client).map(Vec::len)))
+                .collect(),
+            txs: ids
+                .iter()
+                .map(|tx| TxCheckpoint {
+                    tx: *tx,
+                    applied: engine.applied_txs.get(tx).copied(),
+                    tx_client: engine.tx_clients.get(tx).copied(),
+                    disputed: engine.disputed_txs.get(tx).copied(),
+                    dispute_client: engine.dispute_clients.get(tx).copied(),
+                    resolved: engine.resolved_txs.contains(tx),
+                    withdrawal: engine.withdrawal_txs.contains(tx),
+                    charged_back: engine.charged_back_txs.get(tx).copied(),
+                    currency: engine.tx_currencies.get(tx).cloned(),
+                    movement: engine.movements.get(tx).cloned(),
+                    deferred: engine.deferred_txs.contains(tx),
+                })
+                .collect(),
+            order: engine.order.len(),
+            alerts: engine.alerts.len(),
+            warnings: engine.warnings.len(),
+            trace: engine.trace.len(),
+            tx_errors: engine.tx_errors.len(),
+            applied_count: engine.applied_count,
+            deferred: engine.deferred.len(),
+            by_currency: engine
+                .by_currency
+                .iter()
+                .map(|(currency, engine)| (currency.clone(), Checkpoint::save(engine, txs)))
+                .collect(),
+        }
+    }
+
+    fn restore(self, engine: &mut Engine<A>) {
+        for (client, account) in self.accounts {
+            put(&mut engine.accounts, client, account);
+        }
+        for (client, len) in self.history {
+            match len {
+                Some(len) => engine.history.entry(client).or_default().truncate(len),
+                None => {
+                    engine.history.remove(&client);
+                }
+            }
+        }
+        for (client, len) in self.client_errors {
+            match len {
+                Some(len) => engine
+                    .client_errors
+                    .entry(client)
+                    .or_default()
+                    .truncate(len),
+                None => {
+                    engine.client_errors.remove(&client);
+                }
+            }
+        }
+        for saved in self.txs {
+            let tx = saved.tx;
+            put(&mut engine.applied_txs, tx, saved.applied);
+            put(&mut engine.tx_clients, tx, saved.tx_client);
+            put(&mut engine.disputed_txs, tx, saved.disputed);
+            put(&mut engine.dispute_clients, tx, saved.dispute_client);
+            flag(&mut engine.resolved_txs, tx, saved.resolved);
+            flag(&mut engine.withdrawal_txs, tx, saved.withdrawal);
+            put(&mut engine.charged_back_txs, tx, saved.charged_back);
+            put(&mut engine.tx_currencies, tx, saved.currency);
+            put(&mut engine.movements, tx, saved.movement);
+            flag(&mut engine.deferred_txs, tx, saved.deferred);
+        }
+        engine.order.truncate(self.order);
+        engine.alerts.truncate(self.alerts);
+        engine.warnings.truncate(self.warnings);
+        engine.trace.truncate(self.trace);
+        engine.tx_errors.truncate(self.tx_errors);
+        engine.applied_count = self.applied_count;
+        engine.deferred.truncate(self.deferred);
+        let mut by_currency = std::mem::take(&mut engine.by_currency);
+        for (currency, checkpoint) in self.by_currency {
+            if let Some(mut currency_engine) = by_currency.remove(&currency) {
+                checkpoint.restore(&mut currency_engine);
+                engine.by_currency.insert(currency, currency_engine);
+            }
+        }
+    }
+}
+
+/// Sets `map[key]` back to `value`, removing the entry if there was none.
+fn put<K: Eq + Hash, V, S: BuildHasher>(map: &mut HashMap<K, V, S>, key: K, value: Option<V>) {
+    match value {
+        Some(value) => {
+            map.insert(key, value);
+        }
+        None => {
+            map.remove(&key);
+        }
+    }
+}
+
+fn flag<S: BuildHasher>(set: &mut HashSet<u32, S>, tx: u32, present: bool) {
+    if present {
+        set.insert(tx);
+    } else {
+        set.remove(&tx);
     }
+}
+
+fn copy_map<V: Copy>(map: &FastMap<u32, V>) -> HashMap<u32, V> {
+    map.iter().map(|(tx, value)| (*tx, *value)).collect()
+}
 
-    (
-        accounts.into_iter().map(|(_id, account)| account).collect(),
-        tx_errors,
-    )
+/// Like `process_transactions`, but starting from previously snapshotted
+/// account balances instead of empty accounts.
+pub fn process_transactions_from(
+    snapshot: Vec<Account>,
+    transactions: Vec<Transaction>,
+    config: &EngineConfig,
+) -> (Vec<Account>, Vec<String>) {
+    let mut engine = Engine::from_accounts(snapshot, config.clone());
+    // Every transaction may open an account or be disputable later.
+    engine.reserve(transactions.len(), transactions.len());
+    for transaction in transactions {
+        let _ = engine.apply(transaction);
+    }
+    engine.finish()
 }
 
 #[cfg(test)]
@@ -129,13 +1931,15 @@ mod test {
     use hamcrest::*;
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
-    
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
     const TEST_CLIENT_ID: u16 = 42;
 
     #[test]
     fn test_no_transactions() {
         let (accounts, errors) = process_transactions(vec![]);
-        assert_that!(accounts, is(equal_to(vec![])));
+        assert_that!(accounts, is(equal_to(Vec::<Account>::new())));
         assert_eq!(errors.len(), 0);
     }
 
@@ -145,7 +1949,7 @@ mod test {
             "deposit".into(),
             TEST_CLIENT_ID,
             2,
-            dec!(3.1234),
+            Some(dec!(3.1234)),
         )]);
 
         assert_that!(
@@ -163,8 +1967,8 @@ mod test {
     #[test]
     fn test_withdrawal() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, dec!(3.1234)),
-            Transaction::new("withdrawal".into(), TEST_CLIENT_ID, 2, dec!(3.1234)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(3.1234))),
+            Transaction::new("withdrawal".into(), TEST_CLIENT_ID, 3, Some(dec!(3.1234))),
         ]);
 
         assert_that!(
@@ -179,13 +1983,27 @@ mod test {
         assert_eq!(errors.len(), 0);
     }
 
+    #[test]
+    fn test_deposit_without_amount() {
+        let (accounts, errors) = process_transactions(vec![Transaction::new(
+            "deposit".into(),
+            TEST_CLIENT_ID,
+            1,
+            None,
+        )]);
+
+        // Rejected by the validation pass, before an account is opened.
+        assert!(accounts.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
     #[test]
     fn test_withdrawal_from_insufficient_funds() {
         let (accounts, errors) = process_transactions(vec![Transaction::new(
             "withdrawal".into(),
             TEST_CLIENT_ID,
             2,
-            dec!(3.1234),
+            Some(dec!(3.1234)),
         )]);
 
         assert_that!(
@@ -203,8 +2021,8 @@ mod test {
     #[test]
     fn test_dispute() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
         ]);
 
         assert_account(&accounts[0], dec!(0.0), dec!(100.0), dec!(100.0), false);
@@ -212,60 +2030,191 @@ mod test {
     }
 
     #[test]
-    fn test_cannot_dispute_twice() {
+    fn test_cannot_dispute_partially_withdrawn_deposit() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, dec!(100.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("withdrawal".into(), TEST_CLIENT_ID, 2, Some(dec!(60.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
         ]);
 
-        assert_account(&accounts[0], dec!(100.0), dec!(100.0), dec!(200.0), false);
+        assert_account(&accounts[0], dec!(40.0), dec!(0.0), dec!(40.0), false);
         assert_eq!(errors.len(), 1);
     }
 
     #[test]
-    fn test_ignore_dispute_for_unknown_transaction() {
-        let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 999, dec!(0.0)),
-        ]);
+    fn test_cannot_dispute_spent_deposit() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine
+            .apply(Transaction::new(
+                "deposit".into(),
+                TEST_CLIENT_ID,
+                1,
+                Some(dec!(100.0)),
+            ))
+            .unwrap();
+        engine
+            .apply(Transaction::new(
+                "withdrawal".into(),
+                TEST_CLIENT_ID,
+                2,
+                Some(dec!(100.0)),
+            ))
+            .unwrap();
 
-        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
-        assert_eq!(errors.len(), 1);
+        let result = engine.apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None));
+
+        assert_eq!(
+            result,
+            Err(EngineError::CannotDisputeSpentFunds {
+                tx: 1,
+                amount: dec!(100.0),
+                available: dec!(0.0),
+            })
+        );
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(0.0), dec!(0.0), dec!(0.0), false);
     }
 
     #[test]
-    fn test_resolve() {
-        let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, dec!(0.0)),
-            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, dec!(0.0)),
-        ]);
+    fn test_dispute_policies() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("withdrawal".into(), TEST_CLIENT_ID, 2, Some(dec!(60.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+        ];
+        let process_with = |dispute_policy| {
+            let config = EngineConfig {
+                dispute_policy,
+                ..EngineConfig::default()
+            };
+            process_transactions_from(vec![], transactions.clone(), &config)
+        };
 
-        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
-        assert_eq!(errors.len(), 0);
+        let (accounts, errors) = process_with(DisputePolicy::StrictAvailable);
+        assert_account(&accounts[0], dec!(40.0), dec!(0.0), dec!(40.0), false);
+        assert_eq!(errors.len(), 1);
+
+        let (accounts, errors) = process_with(DisputePolicy::AllowNegative);
+        assert_account(&accounts[0], dec!(-60.0), dec!(100.0), dec!(40.0), false);
+        assert!(errors.is_empty());
     }
 
     #[test]
-    fn test_cannot_resolve_twice() {
+    fn test_cannot_dispute_twice() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, dec!(100.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
-            Transaction::new("resolve".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
-            Transaction::new("resolve".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
         ]);
 
-        assert_account(&accounts[0], dec!(200.0), dec!(0.0), dec!(200.0), false);
+        assert_account(&accounts[0], dec!(100.0), dec!(100.0), dec!(200.0), false);
         assert_eq!(errors.len(), 1);
     }
 
     #[test]
-    fn test_ignore_resolve_for_unknown_transaction() {
+    fn test_ignore_dispute_for_unknown_transaction() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("resolve".into(), TEST_CLIENT_ID, 999, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 999, None),
+        ]);
+
+        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_no_account_for_referential_only_client() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+        ]);
+
+        assert_eq!(accounts.len(), 1);
+        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
+        assert_eq!(
+            errors,
+            vec!["client 42 has only referential transactions".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+        ]);
+
+        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_resolve_one_of_two_disputes() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(30.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+        ]);
+
+        assert_account(&accounts[0], dec!(100.0), dec!(30.0), dec!(130.0), false);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_from_other_client_is_rejected() {
+        // Client 2 holds as much as tx 1, so only the ownership check stops it
+        // from releasing its own funds against client 1's dispute.
+        let mut engine = Engine::new(EngineConfig::default());
+        for transaction in &[
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), 2, 2, None),
+            Transaction::new("dispute".into(), 1, 1, None),
+        ] {
+            engine.apply(transaction.clone()).unwrap();
+        }
+        for kind in &["resolve", "chargeback"] {
+            assert_eq!(
+                engine.apply(Transaction::new(kind.to_string(), 2, 1, None)),
+                Err(EngineError::ForeignTransaction { client: 2, tx: 1 })
+            );
+        }
+        assert_eq!(
+            engine.apply(Transaction::new("dispute".into(), 1, 2, None)),
+            Err(EngineError::ForeignTransaction { client: 1, tx: 2 })
+        );
+        assert_eq!(engine.disputed_amount(1), Some(dec!(100.0)));
+        assert_eq!(engine.disputed_amount(2), Some(dec!(100.0)));
+
+        let (accounts, errors) = engine.finish();
+        assert_account(&accounts[0], dec!(0.0), dec!(100.0), dec!(100.0), false);
+        assert_account(&accounts[1], dec!(0.0), dec!(100.0), dec!(100.0), false);
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn test_cannot_resolve_twice() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 2, None),
+        ]);
+
+        assert_account(&accounts[0], dec!(200.0), dec!(0.0), dec!(200.0), false);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_ignore_resolve_for_unknown_transaction() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 999, None),
         ]);
 
         assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
@@ -275,34 +2224,391 @@ mod test {
     #[test]
     fn test_ignore_undisputed_resolve() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+        ]);
+
+        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_cannot_redispute_resolved_by_default() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
         ]);
 
         assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
         assert_eq!(errors.len(), 1);
     }
 
+    #[test]
+    fn test_allow_redispute_resolved() {
+        let config = EngineConfig {
+            allow_redispute: true,
+            ..EngineConfig::default()
+        };
+        let (accounts, errors) = process_transactions_from(
+            vec![],
+            vec![
+                Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+                Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+                Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+                Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            ],
+            &config,
+        );
+
+        assert_account(&accounts[0], dec!(0.0), dec!(100.0), dec!(100.0), false);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_verify_amounts() {
+        let config = EngineConfig {
+            verify_amounts: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(config);
+        for tx in 1..=2 {
+            let deposit = Transaction::new("deposit".into(), TEST_CLIENT_ID, tx, Some(dec!(5.0)));
+            engine.apply(deposit).unwrap();
+            engine
+                .apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, tx, None))
+                .unwrap();
+        }
+
+        assert!(engine
+            .apply(Transaction::new(
+                "resolve".into(),
+                TEST_CLIENT_ID,
+                1,
+                Some(dec!(5.0))
+            ))
+            .is_ok());
+        let mismatch = engine
+            .apply(Transaction::new(
+                "chargeback".into(),
+                TEST_CLIENT_ID,
+                2,
+                Some(dec!(4.0)),
+            ))
+            .unwrap_err();
+        assert_eq!(mismatch.to_string(), "chargeback amount mismatch for tx 2");
+        assert!(engine
+            .apply(Transaction::new(
+                "chargeback".into(),
+                TEST_CLIENT_ID,
+                2,
+                None
+            ))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_amounts_ignored_by_default() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(5.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, Some(dec!(1.0))),
+        ]);
+
+        assert_account(&accounts[0], dec!(5.0), dec!(0.0), dec!(5.0), false);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_redispute_cycles_never_exceed_deposit() {
+        let config = EngineConfig {
+            allow_redispute: true,
+            partial_chargebacks: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(config);
+        engine
+            .apply(Transaction::new(
+                "deposit".into(),
+                TEST_CLIENT_ID,
+                1,
+                Some(dec!(100.0)),
+            ))
+            .unwrap();
+
+        for _ in 0..3 {
+            for kind in &["dispute", "resolve"] {
+                engine
+                    .apply(Transaction::new(kind.to_string(), TEST_CLIENT_ID, 1, None))
+                    .unwrap();
+                assert!(engine.account(TEST_CLIENT_ID).unwrap().held <= dec!(100.0));
+            }
+        }
+        assert!(engine
+            .apply(Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None))
+            .is_err());
+
+        // Once part is charged back the account is locked, so the rest can't
+        // be disputed again.
+        let transactions = vec![
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, Some(dec!(40.0))),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        assert_eq!(
+            engine.apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None)),
+            Err(EngineError::DisputeOnLockedAccount {
+                client: TEST_CLIENT_ID
+            })
+        );
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(60.0), dec!(0.0), dec!(60.0), true);
+    }
+
+    #[test]
+    fn test_chargeback_reversal() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(20.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("chargeback_reversal".into(), TEST_CLIENT_ID, 1, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        // Still locked by the chargeback of tx 2.
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(100.0), dec!(0.0), dec!(100.0), true);
+
+        engine
+            .apply(Transaction::new(
+                "chargeback_reversal".into(),
+                TEST_CLIENT_ID,
+                2,
+                None,
+            ))
+            .unwrap();
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(120.0), dec!(0.0), dec!(120.0), false);
+
+        // The transactions are resolved again, so neither may be reversed
+        // twice or disputed anew.
+        assert_eq!(
+            engine.apply(Transaction::new(
+                "chargeback_reversal".into(),
+                TEST_CLIENT_ID,
+                1,
+                None
+            )),
+            Err(EngineError::NotChargedBack { tx: 1 })
+        );
+        assert_eq!(
+            engine.apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None)),
+            Err(EngineError::AlreadyResolved { tx: 1 })
+        );
+    }
+
+    #[test]
+    fn test_lock_reason() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(5.0))),
+            Transaction::new("deposit".into(), 2, 3, Some(dec!(5.0))),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("dispute".into(), 1, 2, None),
+            Transaction::new("dispute".into(), 2, 3, None),
+            Transaction::new("resolve".into(), 2, 3, None),
+            Transaction::new("chargeback".into(), 1, 2, None),
+            Transaction::new("chargeback".into(), 1, 1, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        assert_eq!(
+            engine.account(1).unwrap().lock_reason.as_deref(),
+            Some("chargeback of tx 2")
+        );
+        assert_eq!(engine.account(2).unwrap().lock_reason, None);
+
+        engine
+            .apply(Transaction::new("chargeback_reversal".into(), 1, 2, None))
+            .unwrap();
+        assert_eq!(
+            engine.account(1).unwrap().lock_reason.as_deref(),
+            Some("chargeback of tx 1")
+        );
+        engine
+            .apply(Transaction::new("chargeback_reversal".into(), 1, 1, None))
+            .unwrap();
+        let account = engine.account(1).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.lock_reason, None);
+    }
+
+    #[test]
+    fn test_dispute_on_locked_account() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(20.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+
+        let result = engine.apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None));
+
+        assert_eq!(
+            result,
+            Err(EngineError::DisputeOnLockedAccount {
+                client: TEST_CLIENT_ID
+            })
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!("Cannot dispute on locked account {}", TEST_CLIENT_ID)
+        );
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(20.0), dec!(0.0), dec!(20.0), true);
+    }
+
+    #[test]
+    fn test_resolve_on_locked_account() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(20.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 2, None),
+        ];
+
+        let (accounts, errors) =
+            process_transactions_from(vec![], transactions.clone(), &EngineConfig::default());
+        assert_account(&accounts[0], dec!(0.0), dec!(20.0), dec!(20.0), true);
+        assert_eq!(
+            errors,
+            vec![format!(
+                "Cannot resolve transaction \"2\" on locked account {}",
+                TEST_CLIENT_ID
+            )]
+        );
+
+        let config = EngineConfig {
+            allow_locked_resolve: true,
+            ..EngineConfig::default()
+        };
+        let (accounts, errors) = process_transactions_from(vec![], transactions, &config);
+        assert_account(&accounts[0], dec!(20.0), dec!(0.0), dec!(20.0), true);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_cannot_redispute_charged_back() {
+        let config = EngineConfig {
+            allow_redispute: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(config);
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+
+        let result = engine.apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None));
+
+        assert_eq!(result, Err(EngineError::FullyChargedBack { tx: 1 }));
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(100.0), dec!(0.0), dec!(100.0), true);
+    }
+
     #[test]
     fn test_chargeback() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, dec!(0.0)),
-            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, None),
         ]);
 
         assert_account(&accounts[0], dec!(0.0), dec!(0.0), dec!(0.0), true);
         assert_eq!(errors.len(), 0);
     }
 
+    #[test]
+    fn test_resolve_after_partial_chargeback() {
+        let config = EngineConfig {
+            partial_chargebacks: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(config);
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, Some(dec!(40.0))),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(0.0), dec!(60.0), dec!(60.0), true);
+
+        engine
+            .apply(Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None))
+            .unwrap();
+
+        let account = engine.account(TEST_CLIENT_ID).unwrap();
+        assert_account(account, dec!(60.0), dec!(0.0), dec!(60.0), true);
+        assert!(engine
+            .apply(Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None))
+            .is_err());
+    }
+
+    #[test]
+    fn test_partial_chargeback_above_held_is_rejected() {
+        let config = EngineConfig {
+            partial_chargebacks: true,
+            ..EngineConfig::default()
+        };
+        let (accounts, errors) = process_transactions_from(
+            vec![],
+            vec![
+                Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+                Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(50.0))),
+                Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+                Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+                Transaction::new("chargeback".into(), TEST_CLIENT_ID, 2, Some(dec!(60.0))),
+            ],
+            &config,
+        );
+
+        assert_account(&accounts[0], dec!(0.0), dec!(150.0), dec!(150.0), false);
+        assert_eq!(errors.len(), 1);
+    }
+
     #[test]
     fn test_cannot_chargeback_twice() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, dec!(100.0)),
-            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
-            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
-            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 2, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(100.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 2, None),
         ]);
 
         assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), true);
@@ -312,8 +2618,8 @@ mod test {
     #[test]
     fn test_ignore_chargeback_for_unknown_transaction() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 999, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 999, None),
         ]);
 
         assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
@@ -323,14 +2629,1504 @@ mod test {
     #[test]
     fn test_ignore_undisputed_chargeback() {
         let (accounts, errors) = process_transactions(vec![
-            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, dec!(100.0)),
-            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, dec!(0.0)),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 1, None),
+        ]);
+
+        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let (mut accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(100.0))),
+            Transaction::new("transfer".into(), 1, 2, Some(dec!(30.0))).with_destination(2),
+        ]);
+        accounts.sort_by_key(|account| account.client);
+
+        assert_account(&accounts[0], dec!(70.0), dec!(0.0), dec!(70.0), false);
+        assert_account(&accounts[1], dec!(30.0), dec!(0.0), dec!(30.0), false);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_transfer_from_insufficient_funds() {
+        let (mut accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(10.0))),
+            Transaction::new("transfer".into(), 1, 3, Some(dec!(150.0))).with_destination(2),
         ]);
+        accounts.sort_by_key(|account| account.client);
 
         assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), false);
+        assert_account(&accounts[1], dec!(10.0), dec!(0.0), dec!(10.0), false);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_first_tx() {
+        let (mut accounts, _) = process_transactions(vec![
+            Transaction::new("deposit".into(), 1, 3, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), 2, 5, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), 1, 7, Some(dec!(10.0))),
+            Transaction::new("transfer".into(), 1, 8, Some(dec!(1.0))).with_destination(3),
+        ]);
+        accounts.sort_by_key(|account| account.client);
+
+        assert_eq!(accounts[0].first_tx, Some(3));
+        assert_eq!(accounts[1].first_tx, Some(5));
+        assert_eq!(accounts[2].first_tx, Some(8));
+    }
+
+    #[test]
+    fn test_dispute_outcome_counts() {
+        let (accounts, errors) = process_transactions(vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(20.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+            Transaction::new("chargeback".into(), TEST_CLIENT_ID, 2, None),
+        ]);
+
+        assert_eq!(accounts[0].resolved_count, 1);
+        assert_eq!(accounts[0].chargeback_count, 1);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_query_account_mid_stream() {
+        let mut engine = Engine::new(EngineConfig::default());
+        assert!(engine.account(TEST_CLIENT_ID).is_none());
+
+        engine
+            .apply(Transaction::new(
+                "deposit".into(),
+                TEST_CLIENT_ID,
+                1,
+                Some(dec!(10.0)),
+            ))
+            .unwrap();
+        assert_account(
+            engine.account(TEST_CLIENT_ID).unwrap(),
+            dec!(10.0),
+            dec!(0.0),
+            dec!(10.0),
+            false,
+        );
+
+        engine
+            .apply(Transaction::new(
+                "withdrawal".into(),
+                TEST_CLIENT_ID,
+                2,
+                Some(dec!(4.0)),
+            ))
+            .unwrap();
+        assert_account(
+            engine.account(TEST_CLIENT_ID).unwrap(),
+            dec!(6.0),
+            dec!(0.0),
+            dec!(6.0),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine
+            .apply(Transaction::new(
+                "deposit".into(),
+                TEST_CLIENT_ID,
+                1,
+                Some(dec!(100.0)),
+            ))
+            .unwrap();
+        engine
+            .apply(Transaction::new(
+                "deposit".into(),
+                TEST_CLIENT_ID,
+                2,
+                Some(dec!(50.0)),
+            ))
+            .unwrap();
+        engine
+            .apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None))
+            .unwrap();
+        let mut saved = Vec::new();
+        engine.save_state(&mut saved).unwrap();
+
+        let mut resumed = Engine::load_state(&saved[..], EngineConfig::default()).unwrap();
+        resumed
+            .apply(Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None))
+            .unwrap();
+        resumed
+            .apply(Transaction::new("resolve".into(), TEST_CLIENT_ID, 2, None))
+            .unwrap();
+
+        let (accounts, errors) = resumed.finish();
+        assert_account(&accounts[0], dec!(50.0), dec!(100.0), dec!(150.0), false);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(40.0))),
+            Transaction::new("withdrawal".into(), 1, 3, Some(dec!(30.0))),
+            Transaction::new("dispute".into(), 2, 2, None),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("withdrawal".into(), 1, 4, Some(dec!(10.0))),
+            Transaction::new("chargeback".into(), 2, 2, None),
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(5.0))),
+        ];
+        let mut uninterrupted = Engine::new(EngineConfig::default());
+        for transaction in transactions.clone() {
+            let _ = uninterrupted.apply(transaction);
+        }
+
+        let (first, rest) = transactions.split_at(transactions.len() / 2);
+        let mut engine = Engine::new(EngineConfig::default());
+        for transaction in first.iter().cloned() {
+            let _ = engine.apply(transaction);
+        }
+        let mut checkpoint = Vec::new();
+        engine.save_state(&mut checkpoint).unwrap();
+        let mut resumed = Engine::load_state(&checkpoint[..], EngineConfig::default()).unwrap();
+        let account = resumed.account(2).unwrap();
+        assert_eq!(resumed.held_breakdown(account), vec![(2, dec!(40.0))]);
+        for transaction in rest.iter().cloned() {
+            let _ = resumed.apply(transaction);
+        }
+
+        let (expected_accounts, expected_errors) = uninterrupted.finish();
+        let (accounts, errors) = resumed.finish();
+        assert_eq!(accounts, expected_accounts);
+        assert_eq!(errors, expected_errors);
+        assert_that!(expected_errors.len(), is(equal_to(2)));
+    }
+
+    #[test]
+    fn test_save_and_load_state_with_currencies() {
+        let in_currency =
+            |transaction_type: &str, client, tx, amount, currency: &str| Transaction {
+                currency: Some(currency.to_string()),
+                ..Transaction::new(transaction_type.into(), client, tx, amount)
+            };
+        let mut engine = Engine::new(EngineConfig::default());
+        let _ = engine.apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(5.0))));
+        let _ = engine.apply(in_currency("deposit", 1, 2, Some(dec!(10.0)), "USD"));
+        let _ = engine.apply(in_currency("deposit", 1, 3, Some(dec!(20.0)), "EUR"));
+        let _ = engine.apply(in_currency("deposit", 2, 4, Some(dec!(7.0)), "EUR"));
+        let _ = engine.apply(Transaction::new("dispute".into(), 1, 3, None));
+        let mut saved = Vec::new();
+        engine.save_state(&mut saved).unwrap();
+
+        let mut resumed = Engine::load_state(&saved[..], EngineConfig::default()).unwrap();
+        assert_eq!(resumed.accounts(), engine.accounts());
+        // The currency of the disputed deposit is still known, so the resolve
+        // needs none.
+        resumed
+            .apply(Transaction::new("resolve".into(), 1, 3, None))
+            .unwrap();
+        resumed
+            .apply(in_currency("withdrawal", 1, 5, Some(dec!(4.0)), "USD"))
+            .unwrap();
+
+        let (accounts, errors) = resumed.finish();
+        assert_eq!(errors.len(), 0);
+        assert_eq!(accounts.len(), 4);
+        let balance = |client, currency: &str| {
+            accounts
+                .iter()
+                .find(|account| {
+                    account.client == client && account.currency.as_deref() == Some(currency)
+                })
+                .map(|account| (account.available, account.held))
+        };
+        assert_eq!(balance(1, "USD"), Some((dec!(6.0), dec!(0))));
+        assert_eq!(balance(1, "EUR"), Some((dec!(20.0), dec!(0))));
+        assert_eq!(balance(2, "EUR"), Some((dec!(7.0), dec!(0))));
+    }
+
+    #[test]
+    fn test_load_state_version() {
+        let mut saved = Vec::new();
+        Engine::new(EngineConfig::default())
+            .save_state(&mut saved)
+            .unwrap();
+        let saved = String::from_utf8(saved).unwrap();
+        assert!(saved.contains("\"version\":2"));
+
+        let unversioned = saved.replace("\"version\":2,", "");
+        assert!(Engine::load_state(unversioned.as_bytes(), EngineConfig::default()).is_ok());
+        let legacy = saved.replace("\"version\":2", "\"version\":1");
+        assert!(Engine::load_state(legacy.as_bytes(), EngineConfig::default()).is_ok());
+
+        let newer = saved.replace("\"version\":2", "\"version\":3");
+        let err = Engine::load_state(newer.as_bytes(), EngineConfig::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "unsupported state version 3 (expected 2)");
+    }
+
+    #[test]
+    fn test_upgrade_version_1_state() {
+        // Saved with the owner of each deposit, but not of its dispute.
+        let legacy = r#"{"version":1,"accounts":[{"client":1,"available":"0","held":"100","total":"100","locked":false},{"client":2,"available":"5","held":"0","total":"5","locked":false}],"applied_txs":{"1":"100","2":"5"},"disputed_txs":{"1":"100"},"resolved_txs":[],"tx_clients":{"1":1,"2":2}}"#;
+        let mut resumed = Engine::load_state(legacy.as_bytes(), EngineConfig::default()).unwrap();
+
+        let account = resumed.account(1).unwrap().clone();
+        assert_eq!(resumed.held_breakdown(&account), vec![(1, dec!(100))]);
+        assert_eq!(
+            resumed.apply(Transaction::new("resolve".into(), 2, 1, None)),
+            Err(EngineError::ForeignTransaction { client: 2, tx: 1 })
+        );
+        resumed
+            .apply(Transaction::new("resolve".into(), 1, 1, None))
+            .unwrap();
+        assert_account(
+            resumed.account(1).unwrap(),
+            dec!(100),
+            dec!(0),
+            dec!(100),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_resolve_from_legacy_state() {
+        // Saved before dispute and transaction owners were tracked.
+        let legacy = r#"{"version":1,"accounts":[{"client":1,"available":"0","held":"150","total":"150","locked":false}],"applied_txs":{"1":"100","2":"50"},"disputed_txs":{"1":"100","2":"50"},"resolved_txs":[]}"#;
+        let mut resumed = Engine::load_state(legacy.as_bytes(), EngineConfig::default()).unwrap();
+
+        resumed
+            .apply(Transaction::new("resolve".into(), 1, 1, None))
+            .unwrap();
+        resumed
+            .apply(Transaction::new("chargeback".into(), 1, 2, None))
+            .unwrap();
+
+        let (accounts, errors) = resumed.finish();
+        assert_account(&accounts[0], dec!(100.0), dec!(0.0), dec!(100.0), true);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_merge_shards() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(20.0))),
+            Transaction::new("withdrawal".into(), 1, 3, Some(dec!(4.0))),
+            Transaction::new("dispute".into(), 2, 2, None),
+            Transaction::new("deposit".into(), 3, 4, Some(dec!(5.0))),
+            Transaction::new("dispute".into(), 3, 4, None),
+            Transaction::new("chargeback".into(), 3, 4, None),
+            Transaction::new("withdrawal".into(), 1, 5, Some(dec!(100.0))),
+        ];
+        let (expected, expected_errors) = process_transactions(transactions.clone());
+
+        let mut shards = vec![
+            Engine::new(EngineConfig::default()),
+            Engine::new(EngineConfig::default()),
+        ];
+        for transaction in transactions {
+            let shard = usize::from(transaction.client % 2);
+            let _ = shards[shard].apply(transaction);
+        }
+        let mut merged = shards.remove(0);
+        merged.merge(shards.remove(0));
+        let (accounts, errors) = merged.finish();
+
+        assert_eq!(accounts, expected);
+        assert_eq!(errors, expected_errors);
+    }
+
+    #[test]
+    fn test_merge_same_client() {
+        let mut first = Engine::new(EngineConfig::default());
+        let _ = first.apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))));
+        let _ = first.apply(Transaction::new("dispute".into(), 1, 1, None));
+        let mut second = Engine::new(EngineConfig::default());
+        let _ = second.apply(Transaction::new("deposit".into(), 1, 2, Some(dec!(5.0))));
+        let _ = second.apply(Transaction::new("dispute".into(), 1, 2, None));
+        let _ = second.apply(Transaction::new("chargeback".into(), 1, 2, None));
+
+        first.merge(second);
+
+        let account = first.account(1).unwrap();
+        assert_account(account, dec!(0.0), dec!(10.0), dec!(10.0), true);
+        // Dispute bookkeeping of both shards carries over, so the second
+        // shard's chargeback keeps the first's dispute from being resolved.
+        assert_eq!(first.disputed_amount(1), Some(dec!(10.0)));
+        assert_eq!(
+            first.apply(Transaction::new("resolve".into(), 1, 1, None)),
+            Err(EngineError::ResolveOnLockedAccount { client: 1, tx: 1 })
+        );
+    }
+
+    #[test]
+    fn test_merge_deferred_dispute() {
+        let config = EngineConfig {
+            defer_unresolved: true,
+            ..EngineConfig::default()
+        };
+        let mut first = Engine::new(config.clone());
+        let _ = first.apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))));
+        // The deposit went to the other shard, so the dispute waits for it.
+        let mut second = Engine::new(config);
+        let _ = second.apply(Transaction::new("dispute".into(), 1, 1, None));
+
+        first.merge(second);
+        assert_eq!(first.applied_count, 2);
+        let (accounts, errors) = first.finish();
+
+        assert_account(&accounts[0], dec!(0.0), dec!(10.0), dec!(10.0), false);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_process_with_caller_state() {
+        let mut accounts = HashMap::new();
+        let mut ledger = Ledger::default();
+
+        let errors = process_transactions_with_state(
+            vec![
+                Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+                Transaction::new("dispute".into(), 1, 1, None),
+            ],
+            &mut accounts,
+            &mut ledger,
+        );
+        assert!(errors.is_empty());
+        assert_account(&accounts[&1], dec!(0.0), dec!(10.0), dec!(10.0), false);
+
+        let errors = process_transactions_with_state(
+            vec![
+                Transaction::new("resolve".into(), 1, 1, None),
+                Transaction::new("deposit".into(), 1, 2, Some(dec!(5.0))),
+                Transaction::new("withdrawal".into(), 1, 3, Some(dec!(50.0))),
+            ],
+            &mut accounts,
+            &mut ledger,
+        );
+        assert_eq!(errors.len(), 1);
+        assert_account(&accounts[&1], dec!(15.0), dec!(0.0), dec!(15.0), false);
+        assert!(ledger.resolved_txs.contains(&1));
+        assert!(ledger.disputed_txs.is_empty());
+        assert_eq!(ledger.applied_txs.len(), 2);
+
+        let mut in_euros = Transaction::new("deposit".into(), 1, 4, Some(dec!(5.0)));
+        in_euros.currency = Some("EUR".into());
+        let errors = process_transactions_with_state(vec![in_euros], &mut accounts, &mut ledger);
+        assert_eq!(errors, vec![EngineError::CurrencyWithoutEngine { tx: 4 }]);
+        assert_account(&accounts[&1], dec!(15.0), dec!(0.0), dec!(15.0), false);
+        assert_eq!(ledger.applied_txs.len(), 2);
+    }
+
+    #[test]
+    fn test_largest_undisputed() {
+        let config = EngineConfig {
+            track_undisputed: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(config);
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(500.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(900.0))),
+            Transaction::new("deposit".into(), 1, 3, Some(dec!(50.0))),
+            Transaction::new("withdrawal".into(), 1, 4, Some(dec!(300.0))),
+            Transaction::new("dispute".into(), 2, 2, None),
+            Transaction::new("resolve".into(), 2, 2, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+
+        let largest = engine.largest_undisputed(2);
+
+        assert_eq!(
+            largest,
+            vec![
+                &Movement {
+                    tx: 1,
+                    client: 1,
+                    transaction_type: "deposit".into(),
+                    amount: dec!(500.0),
+                    disputed: false,
+                },
+                &Movement {
+                    tx: 4,
+                    client: 1,
+                    transaction_type: "withdrawal".into(),
+                    amount: dec!(300.0),
+                    disputed: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_type() {
+        let engine = Engine::new(EngineConfig {
+            aliases: vec![("credit".to_string(), "deposit".to_string())]
+                .into_iter()
+                .collect(),
+            ..EngineConfig::default()
+        });
+
+        assert_eq!(
+            engine.validate(&Transaction::new("refund".into(), 1, 1, Some(dec!(1)))),
+            Err(EngineError::UnhandledType("refund".to_string()))
+        );
+        assert_eq!(
+            engine.validate(&Transaction::new("credit".into(), 1, 1, Some(dec!(1)))),
+            Ok(())
+        );
+        // Validating never opens an account.
+        assert!(engine.accounts().is_empty());
+    }
+
+    #[test]
+    fn test_chained_aliases_map_once() {
+        let mut engine = Engine::new(EngineConfig {
+            aliases: vec![
+                ("hold".to_string(), "dispute".to_string()),
+                ("dispute".to_string(), "withdrawal".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            ..EngineConfig::default()
+        });
+        let hold = Transaction::new("hold".into(), 1, 1, None);
+
+        assert_eq!(engine.validate(&hold), Ok(()));
+        assert_eq!(
+            engine.validate(&Transaction::new("dispute".into(), 1, 1, None)),
+            Err(EngineError::MissingAmount {
+                transaction_type: "withdrawal".to_string(),
+                tx: 1
+            })
+        );
+        let _ = engine.apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(10))));
+        // Checked as the dispute it maps to, not as a withdrawal.
+        engine.apply(hold).unwrap();
+        assert_account(
+            engine.account(1).unwrap(),
+            dec!(0),
+            dec!(10),
+            dec!(10),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_validate_amount_presence() {
+        let engine = Engine::new(EngineConfig::default());
+
+        assert_eq!(
+            engine.validate(&Transaction::new("withdrawal".into(), 1, 1, None)),
+            Err(EngineError::MissingAmount {
+                transaction_type: "withdrawal".to_string(),
+                tx: 1
+            })
+        );
+        assert_eq!(
+            engine.validate(&Transaction::new("dispute".into(), 1, 1, None)),
+            Ok(())
+        );
+        let mut transfer = Transaction::new("transfer".into(), 1, 2, Some(dec!(1)));
+        assert_eq!(
+            engine.validate(&transfer),
+            Err(EngineError::IncompleteTransfer { tx: 2 })
+        );
+        transfer.destination = Some(1);
+        assert_eq!(
+            engine.validate(&transfer),
+            Err(EngineError::SelfTransfer { tx: 2 })
+        );
+        transfer.destination = Some(2);
+        assert_eq!(engine.validate(&transfer), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_amount_sign() {
+        let chargeback = Transaction::new("chargeback".into(), 1, 1, Some(dec!(-1)));
+        let engine = Engine::new(EngineConfig::default());
+
+        assert_eq!(
+            engine.validate(&Transaction::new("deposit".into(), 1, 1, Some(dec!(-1)))),
+            Err(EngineError::NegativeAmount {
+                transaction_type: "deposit".to_string(),
+                tx: 1,
+                amount: dec!(-1)
+            })
+        );
+        // Only partial chargebacks move the amount they carry.
+        assert_eq!(engine.validate(&chargeback), Ok(()));
+        let engine = Engine::new(EngineConfig {
+            partial_chargebacks: true,
+            ..EngineConfig::default()
+        });
+        assert!(matches!(
+            engine.validate(&chargeback),
+            Err(EngineError::NegativeAmount { .. })
+        ));
+    }
+
+    #[test]
+    fn test_validate_reserved_client() {
+        let deposit = Transaction::new("deposit".into(), 0, 1, Some(dec!(1)));
+        let mut transfer = Transaction::new("transfer".into(), 1, 2, Some(dec!(1)));
+        transfer.destination = Some(0);
+        assert_eq!(
+            Engine::new(EngineConfig::default()).validate(&deposit),
+            Ok(())
+        );
+
+        let mut engine = Engine::new(EngineConfig {
+            reserve_client_zero: true,
+            ..EngineConfig::default()
+        });
+        engine
+            .apply(Transaction::new("deposit".into(), 1, 3, Some(dec!(5))))
+            .unwrap();
+        assert_eq!(
+            engine.apply(deposit),
+            Err(EngineError::ReservedClient { tx: 1 })
+        );
+        assert_eq!(
+            engine.apply(transfer),
+            Err(EngineError::ReservedClient { tx: 2 })
+        );
+
+        let (accounts, errors) = engine.finish();
+        assert_eq!(accounts, vec![Account::new(1, dec!(5), dec!(0), false)]);
+        assert_eq!(
+            errors,
+            vec![
+                "Could not apply transaction \"1\": client 0 is reserved",
+                "Could not apply transaction \"2\": client 0 is reserved"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_amount_range() {
+        let engine = Engine::new(EngineConfig::default());
+
+        assert_eq!(
+            engine.validate(&Transaction::new("deposit".into(), 1, 1, Some(MAX_AMOUNT))),
+            Ok(())
+        );
+        let err = engine
+            .validate(&Transaction::new(
+                "deposit".into(),
+                1,
+                1,
+                Some(MAX_AMOUNT + dec!(1)),
+            ))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Amount 1000000000000000001 for deposit transaction \"1\" exceeds the maximum of 1000000000000000000"
+        );
+    }
+
+    #[test]
+    fn test_transaction_block_rollback() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine
+            .apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(10))))
+            .unwrap();
+        let block = vec![
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(5))),
+            Transaction::new("deposit".into(), 2, 3, Some(dec!(7))),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("withdrawal".into(), 1, 4, Some(dec!(100))),
+        ];
+
+        let err = engine.transaction_block(&block).unwrap_err();
+
+        assert!(matches!(err, EngineError::WithdrawalRejected { tx: 4, .. }));
+        assert_eq!(
+            engine.accounts(),
+            vec![Account::new(1, dec!(10), dec!(0), false)]
+        );
+        assert_eq!(engine.disputed_amount(1), None);
+        let mut euros = Transaction::new("deposit".into(), 1, 5, Some(dec!(3)));
+        euros.currency = Some("EUR".to_string());
+        assert!(engine
+            .transaction_block(&[euros, block[3].clone()])
+            .is_err());
+        assert_eq!(engine.accounts().len(), 1);
+
+        // Nothing of the block is left, so it applies anew once fixed.
+        let mut block = block;
+        block[3].amount = Some(dec!(1));
+        engine.transaction_block(&block).unwrap();
+        let (accounts, errors) = engine.finish();
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(1, dec!(4), dec!(10), false),
+                Account::new(2, dec!(7), dec!(0), false)
+            ]
+        );
+        assert_eq!(
+            errors,
+            vec!["Error when handling transaction \"4\": Insufficient available funds"; 2]
+        );
+    }
+
+    #[test]
+    fn test_apply_with_callback() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("withdrawal".into(), 1, 2, Some(dec!(50.0))),
+            Transaction::new("deposit".into(), 2, 3, Some(dec!(5.0))),
+            Transaction::new("dispute".into(), 1, 9, None),
+        ];
+        let mut deposits = 0;
+        let mut errors = Vec::new();
+        for transaction in transactions {
+            let _ = engine.apply_with(transaction, |transaction, result| match result {
+                Ok(()) if transaction.transaction_type == "deposit" => deposits += 1,
+                Ok(()) => {}
+                Err(err) => errors.push((transaction.tx, err)),
+            });
+        }
+
+        assert_eq!(deposits, 2);
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    2,
+                    EngineError::WithdrawalRejected {
+                        tx: 2,
+                        reason: "Insufficient available funds".to_string()
+                    }
+                ),
+                (9, EngineError::UnknownTransaction { tx: 9 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_before_apply_hook() {
+        let steps = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = Engine::new(EngineConfig::default());
+        let recorded = Rc::clone(&steps);
+        engine.set_before_apply(move |engine, transaction| {
+            let held = engine.account(TEST_CLIENT_ID).map(|account| account.held);
+            recorded.borrow_mut().push((
+                transaction.transaction_type.clone(),
+                held,
+                engine.disputed_amount(1),
+            ));
+        });
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(100.0))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(5.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 3, Some(dec!(5.0))),
+            Transaction::new("resolve".into(), TEST_CLIENT_ID, 1, None),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 4, Some(dec!(5.0))),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+
+        let zero = Some(dec!(0));
+        assert_eq!(
+            *steps.borrow(),
+            vec![
+                ("deposit".to_string(), None, None),
+                ("deposit".to_string(), zero, None),
+                ("dispute".to_string(), zero, None),
+                ("deposit".to_string(), Some(dec!(100.0)), Some(dec!(100.0))),
+                ("resolve".to_string(), Some(dec!(100.0)), Some(dec!(100.0))),
+                ("deposit".to_string(), Some(dec!(0.0)), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(10.0))),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("withdrawal".into(), 2, 3, Some(dec!(50.0))),
+            Transaction::new("transfer".into(), 2, 4, Some(dec!(1.0))).with_destination(3),
+        ];
+        for transaction in transactions {
+            let _ = engine.apply(transaction);
+        }
+
+        let last_modified = |client| engine.account(client).unwrap().last_modified;
+        assert_eq!(last_modified(1), Some(3));
+        // The rejected withdrawal (index 4) doesn't count.
+        assert_eq!(last_modified(2), Some(5));
+        assert_eq!(last_modified(3), Some(5));
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut engine = Engine::with_capacity(100_000, 1_000, EngineConfig::default());
+        assert!(engine.accounts.capacity() > usize::from(u16::MAX));
+        assert!(engine.applied_txs.capacity() >= 1_000);
+
+        engine
+            .apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(1.0))))
+            .unwrap();
+        let (accounts, errors) = engine.finish();
+        assert_eq!(accounts, vec![Account::new(1, dec!(1.0), dec!(0), false)]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_history() {
+        let mut engine = Engine::new(EngineConfig {
+            record_history: true,
+            ..EngineConfig::default()
+        });
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(10.0))),
+            Transaction::new("withdrawal".into(), TEST_CLIENT_ID, 2, Some(dec!(4.0))),
+            Transaction::new("dispute".into(), TEST_CLIENT_ID, 2, None),
+        ];
+        for transaction in transactions.clone() {
+            engine.apply(transaction).unwrap();
+        }
+
+        assert_eq!(engine.history()[&TEST_CLIENT_ID], transactions);
+        assert_eq!(
+            engine.history_json().unwrap(),
+            "{\"42\":[{\"type\":\"deposit\",\"client\":42,\"tx\":1,\"amount\":\"10.0\"},\
+             {\"type\":\"withdrawal\",\"client\":42,\"tx\":2,\"amount\":\"4.0\"},\
+             {\"type\":\"dispute\",\"client\":42,\"tx\":2,\"amount\":null}]}"
+        );
+    }
+
+    #[test]
+    fn test_history_off_by_default() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine
+            .apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(1.0))))
+            .unwrap();
+
+        assert!(engine.history().is_empty());
+    }
+
+    #[test]
+    fn test_large_deposit_alert() {
+        let mut engine = Engine::new(EngineConfig {
+            large_deposit_threshold: Some(dec!(10000)),
+            ..EngineConfig::default()
+        });
+        engine
+            .apply(Transaction::new(
+                "deposit".into(),
+                TEST_CLIENT_ID,
+                4,
+                Some(dec!(9999)),
+            ))
+            .unwrap();
+        engine
+            .apply(Transaction::new(
+                "deposit".into(),
+                TEST_CLIENT_ID,
+                5,
+                Some(dec!(50000)),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            engine.alerts(),
+            ["large deposit: client 42 tx 5 amount 50000".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_precision() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 1, Some(dec!(1.2345))),
+            Transaction::new("deposit".into(), TEST_CLIENT_ID, 2, Some(dec!(1.0051))),
+        ];
+        let at = |precision| EngineConfig {
+            precision,
+            ..EngineConfig::default()
+        };
+
+        let (accounts, _) = process_transactions_from(vec![], transactions.clone(), &at(4));
+        assert_account(&accounts[0], dec!(2.2396), dec!(0), dec!(2.2396), false);
+
+        let (accounts, _) = process_transactions_from(vec![], transactions, &at(2));
+        assert_account(&accounts[0], dec!(2.24), dec!(0), dec!(2.24), false);
+    }
+
+    #[test]
+    fn test_minor_units_engine() {
+        let config = EngineConfig {
+            precision: 2,
+            ..EngineConfig::default()
+        };
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.5))),
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(2.254))),
+            Transaction::new("withdrawal".into(), 1, 3, Some(dec!(0.25))),
+            Transaction::new("dispute".into(), 1, 2, None),
+            Transaction::new("chargeback".into(), 1, 2, None),
+            Transaction::new("deposit".into(), 2, 4, Some(dec!(1))),
+            Transaction::new("withdrawal".into(), 2, 5, Some(dec!(1.01))),
+        ];
+
+        // The same run in cents, e.g. 2.254 is rounded to 225.
+        let mut engine = Engine::<i64>::with_config(config.clone());
+        for transaction in transactions.clone() {
+            let _ = engine.apply(transaction);
+        }
+        assert_eq!(
+            engine.apply(Transaction::new("deposit".into(), 2, 6, Some(dec!(1e17)))),
+            Err(EngineError::AmountOverflow {
+                transaction_type: "deposit".into(),
+                tx: 6,
+                amount: dec!(1e17),
+            })
+        );
+        let (accounts, errors) = engine.finish();
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(1, 1025, 0, true),
+                Account::new(2, 100, 0, false)
+            ]
+        );
+        assert_eq!(errors.len(), 2);
+
+        let (accounts, errors) = process_transactions_from(Vec::new(), transactions, &config);
+        assert_account(&accounts[0], dec!(10.25), dec!(0), dec!(10.25), true);
+        assert_account(&accounts[1], dec!(1), dec!(0), dec!(1), false);
         assert_eq!(errors.len(), 1);
     }
 
+    #[test]
+    fn test_eight_decimal_places() {
+        let mut engine = Engine::new(EngineConfig {
+            precision: 8,
+            ..EngineConfig::default()
+        });
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(0.12345678))),
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(1234567890.00000009))),
+            Transaction::new("withdrawal".into(), 1, 3, Some(dec!(0.00000007))),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("deposit".into(), 2, 4, Some(dec!(1.00000001))),
+            Transaction::new("withdrawal".into(), 2, 5, Some(dec!(0.00000002))),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        assert_account(
+            engine.account(1).unwrap(),
+            dec!(1234567890.00000002),
+            dec!(0.12345678),
+            dec!(1234567890.1234568),
+            false,
+        );
+
+        engine
+            .apply(Transaction::new("chargeback".into(), 1, 1, None))
+            .unwrap();
+        // Disputing 1.00000001 of 0.99999999 available is one unit short.
+        assert!(engine
+            .apply(Transaction::new("dispute".into(), 2, 4, None))
+            .is_err());
+
+        let (accounts, _) = engine.finish();
+        assert_account(
+            &accounts[0],
+            dec!(1234567890.00000002),
+            dec!(0),
+            dec!(1234567890.00000002),
+            true,
+        );
+        assert_account(
+            &accounts[1],
+            dec!(0.99999999),
+            dec!(0),
+            dec!(0.99999999),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_normalize_balances() {
+        let snapshot = vec![Account::new(TEST_CLIENT_ID, dec!(1.123456), dec!(0), false)];
+        let transactions = vec![Transaction::new(
+            "deposit".into(),
+            TEST_CLIENT_ID,
+            1,
+            Some(dec!(1.1)),
+        )];
+
+        let (accounts, _) = process_transactions_from(
+            snapshot.clone(),
+            transactions.clone(),
+            &EngineConfig::default(),
+        );
+        assert_eq!(accounts[0].max_scale(), 6);
+
+        let config = EngineConfig {
+            normalize_balances: true,
+            ..EngineConfig::default()
+        };
+        let (accounts, _) = process_transactions_from(snapshot, transactions, &config);
+        assert!(accounts[0].max_scale() <= 4);
+        assert_account(&accounts[0], dec!(2.2235), dec!(0), dec!(2.2235), false);
+    }
+
+    #[test]
+    fn test_reused_tx_ids() {
+        let mut engine = Engine::new(EngineConfig::default());
+        engine
+            .apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))))
+            .unwrap();
+        engine
+            .apply(Transaction::new("withdrawal".into(), 1, 2, Some(dec!(1.0))))
+            .unwrap();
+
+        assert_eq!(
+            engine.apply(Transaction::new("withdrawal".into(), 1, 1, Some(dec!(4.0)))),
+            Err(EngineError::TransactionTypeConflict {
+                tx: 1,
+                transaction_type: "withdrawal".into(),
+                original_type: "deposit",
+            })
+        );
+        assert_eq!(
+            engine.apply(Transaction::new("deposit".into(), 2, 2, Some(dec!(4.0)))),
+            Err(EngineError::TransactionTypeConflict {
+                tx: 2,
+                transaction_type: "deposit".into(),
+                original_type: "withdrawal",
+            })
+        );
+        assert_eq!(
+            engine.apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(4.0)))),
+            Err(EngineError::DuplicateTransaction {
+                tx: 1,
+                transaction_type: "deposit".into(),
+            })
+        );
+        // The dispute still holds the original withdrawal's amount.
+        engine
+            .apply(Transaction::new("dispute".into(), 1, 2, None))
+            .unwrap();
+
+        let (accounts, errors) = engine.finish();
+        assert_eq!(accounts, vec![Account::new(1, dec!(8.0), dec!(1.0), false)]);
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut engine = Engine::new(EngineConfig {
+            record_trace: true,
+            ..EngineConfig::default()
+        });
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("chargeback".into(), 1, 1, None),
+            Transaction::new("withdrawal".into(), 1, 2, Some(dec!(1.0))),
+        ];
+        for transaction in transactions {
+            let _ = engine.apply(transaction);
+        }
+
+        assert_eq!(
+            engine.trace(),
+            [
+                "tx 1 deposit client 1: available 0 -> 10, held 0 -> 0, total 0 -> 10, locked false -> false",
+                "tx 1 dispute client 1: available 10 -> 0, held 0 -> 10, total 10 -> 10, locked false -> false",
+                "tx 1 chargeback client 1: available 0 -> 0, held 10 -> 0, total 10 -> 0, locked false -> true",
+                "tx 2 withdrawal client 1: available 0 -> 0, held 0 -> 0, total 0 -> 0, locked true -> true (rejected)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_currencies() {
+        let in_currency = |kind: &str, tx, amount, currency: &str| Transaction {
+            currency: Some(currency.to_string()),
+            ..Transaction::new(kind.into(), 1, tx, amount)
+        };
+        let transactions = vec![
+            in_currency("deposit", 1, Some(dec!(10.0)), "USD"),
+            in_currency("deposit", 2, Some(dec!(5.0)), "EUR"),
+            Transaction::new("deposit".into(), 1, 3, Some(dec!(1.0))),
+            // Disputes without a currency use the referenced transaction's.
+            Transaction::new("dispute".into(), 1, 1, None),
+            in_currency("withdrawal", 4, Some(dec!(6.0)), "EUR"),
+        ];
+
+        let (accounts, errors) = process_transactions(transactions);
+
+        let mut usd = Account::new(1, dec!(0.0), dec!(10.0), false);
+        usd.currency = Some("USD".into());
+        let mut eur = Account::new(1, dec!(5.0), dec!(0), false);
+        eur.currency = Some("EUR".into());
+        assert_eq!(
+            accounts,
+            vec![Account::new(1, dec!(1.0), dec!(0), false), eur, usd]
+        );
+        assert_eq!(
+            errors,
+            vec!["Error when handling transaction \"4\": Insufficient available funds"]
+        );
+    }
+
+    #[test]
+    fn test_inspect_bookkeeping() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(2.0))),
+            Transaction::new("dispute".into(), 1, 1, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+
+        let mut applied: Vec<u32> = engine.applied().collect();
+        applied.sort_unstable();
+        assert_eq!(applied, vec![1, 2]);
+        assert_eq!(engine.disputed().collect::<Vec<_>>(), vec![(1, dec!(10.0))]);
+
+        engine
+            .apply(Transaction::new("resolve".into(), 1, 1, None))
+            .unwrap();
+        assert_eq!(engine.disputed().count(), 0);
+        assert_eq!(engine.applied().count(), 2);
+    }
+
+    #[test]
+    fn test_defer_unresolved() {
+        let transactions = vec![
+            Transaction::new("dispute".into(), 1, 5, None),
+            Transaction::new("deposit".into(), 2, 1, Some(dec!(3.0))),
+            Transaction::new("dispute".into(), 2, 9, None),
+            Transaction::new("deposit".into(), 1, 5, Some(dec!(10.0))),
+            Transaction::new("resolve".into(), 1, 5, None),
+            Transaction::new("dispute".into(), 1, 5, None),
+        ];
+        let (_, errors) = process_transactions(transactions.clone());
+        assert_eq!(errors.len(), 3);
+
+        let mut engine = Engine::new(EngineConfig {
+            defer_unresolved: true,
+            allow_redispute: true,
+            ..EngineConfig::default()
+        });
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        // Held until the end, so the deposit is not disputed yet.
+        assert_eq!(engine.disputed().count(), 0);
+
+        let (accounts, errors) = engine.finish();
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(1, dec!(0.0), dec!(10.0), false),
+                Account::new(2, dec!(3.0), dec!(0), false),
+            ]
+        );
+        assert_eq!(
+            errors,
+            vec!["Could not find applied transaction \"9\" to dispute"]
+        );
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let config = EngineConfig {
+            max_clients: Some(2),
+            ..EngineConfig::default()
+        };
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(5.0))),
+            Transaction::new("deposit".into(), 3, 3, Some(dec!(1.0))),
+            Transaction::new("withdrawal".into(), 1, 4, Some(dec!(2.0))),
+            Transaction {
+                destination: Some(4),
+                ..Transaction::new("transfer".into(), 2, 5, Some(dec!(1.0)))
+            },
+        ];
+
+        let (accounts, errors) = process_transactions_from(vec![], transactions, &config);
+
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(1, dec!(8.0), dec!(0), false),
+                Account::new(2, dec!(5.0), dec!(0), false),
+            ]
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "Could not open an account for client 3: limit of 2 clients reached",
+                "Could not open an account for client 4: limit of 2 clients reached",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_clients_across_currencies() {
+        let in_currency = |client, tx, currency: &str| Transaction {
+            currency: Some(currency.to_string()),
+            ..Transaction::new("deposit".into(), client, tx, Some(dec!(1.0)))
+        };
+        let mut engine = Engine::new(EngineConfig {
+            max_clients: Some(2),
+            ..EngineConfig::default()
+        });
+
+        engine.apply(in_currency(1, 1, "USD")).unwrap();
+        engine.apply(in_currency(2, 2, "EUR")).unwrap();
+        let too_many = Err(EngineError::TooManyClients { client: 3, max: 2 });
+        assert_eq!(engine.apply(in_currency(3, 3, "USD")), too_many);
+        assert_eq!(engine.apply(in_currency(3, 4, "EUR")), too_many);
+        assert_eq!(
+            engine.apply(Transaction::new("deposit".into(), 3, 5, Some(dec!(1.0)))),
+            too_many
+        );
+        // Accounts already open keep processing.
+        engine.apply(in_currency(1, 6, "USD")).unwrap();
+
+        assert_eq!(engine.accounts().len(), 2);
+    }
+
+    #[test]
+    fn test_negative_amounts() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(100.0))),
+            Transaction::new("withdrawal".into(), 1, 2, Some(dec!(-50))),
+            Transaction::new("deposit".into(), 1, 3, Some(dec!(-1.0))),
+            Transaction::new("transfer".into(), 1, 4, Some(dec!(-5.0))).with_destination(2),
+        ];
+
+        let (accounts, errors) = process_transactions(transactions);
+
+        assert_eq!(accounts, vec![Account::new(1, dec!(100.0), dec!(0), false)]);
+        assert_eq!(
+            errors,
+            vec![
+                "Negative amount -50 for withdrawal transaction \"2\"",
+                "Negative amount -1 for deposit transaction \"3\"",
+                "Negative amount -5 for transfer transaction \"4\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_all() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let rows: Vec<Result<Transaction, String>> = vec![
+            Ok(Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0)))),
+            Err("Skipping malformed row at line 3: invalid amount".into()),
+            Ok(Transaction::new(
+                "withdrawal".into(),
+                1,
+                2,
+                Some(dec!(20.0)),
+            )),
+            Ok(Transaction::new("withdrawal".into(), 1, 3, Some(dec!(4.0)))),
+        ];
+
+        let errors = engine.apply_all(rows);
+
+        let expected = vec![
+            "Skipping malformed row at line 3: invalid amount",
+            "Error when handling transaction \"2\": Insufficient available funds",
+        ];
+        assert_eq!(errors, expected);
+        let (accounts, tx_errors) = engine.finish();
+        assert_eq!(accounts, vec![Account::new(1, dec!(6.0), dec!(0), false)]);
+        assert_eq!(tx_errors, expected);
+    }
+
+    #[test]
+    fn test_integer_and_decimal_amounts() {
+        let history = |deposit: Decimal, withdrawal: Decimal, deposit_again: Decimal| {
+            vec![
+                Transaction::new("deposit".into(), 1, 1, Some(deposit)),
+                Transaction::new("withdrawal".into(), 1, 2, Some(withdrawal)),
+                Transaction::new("deposit".into(), 1, 3, Some(deposit_again)),
+                Transaction::new("dispute".into(), 1, 3, None),
+                Transaction::new("resolve".into(), 1, 3, None),
+                Transaction::new("dispute".into(), 1, 1, Some(dec!(100))),
+                Transaction::new("chargeback".into(), 1, 1, Some(dec!(100.0000))),
+            ]
+        };
+        let config = EngineConfig {
+            verify_amounts: true,
+            ..EngineConfig::default()
+        };
+        let (integers, errors) =
+            process_transactions_from(vec![], history(dec!(100), dec!(40), dec!(50)), &config);
+        assert!(errors.is_empty());
+        let (decimals, errors) = process_transactions_from(
+            vec![],
+            history(dec!(100.0000), dec!(40.00), dec!(50.0)),
+            &config,
+        );
+        assert!(errors.is_empty());
+
+        assert_eq!(integers, decimals);
+        assert_account(&decimals[0], dec!(10), dec!(0), dec!(10), true);
+        let write = |accounts: Vec<Account>| {
+            let mut out = Vec::new();
+            crate::helpers::process_output(
+                &mut out,
+                accounts,
+                vec![],
+                &crate::helpers::OutputConfig::default(),
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(write(decimals), write(integers));
+    }
+
+    #[test]
+    fn test_overdraft() {
+        let mut engine = Engine::new(EngineConfig {
+            overdraft: dec!(50),
+            ..EngineConfig::default()
+        });
+        engine
+            .apply(Transaction::new("deposit".into(), 1, 1, Some(dec!(20.0))))
+            .unwrap();
+        engine
+            .apply(Transaction::new(
+                "withdrawal".into(),
+                1,
+                2,
+                Some(dec!(50.0)),
+            ))
+            .unwrap();
+        assert_account(
+            engine.account(1).unwrap(),
+            dec!(-30),
+            dec!(0),
+            dec!(-30),
+            false,
+        );
+
+        assert_eq!(
+            engine.apply(Transaction::new(
+                "withdrawal".into(),
+                1,
+                3,
+                Some(dec!(30.0))
+            )),
+            Err(EngineError::WithdrawalRejected {
+                tx: 3,
+                reason: "Insufficient available funds".to_string()
+            })
+        );
+        let (accounts, errors) = engine.finish();
+        assert_account(&accounts[0], dec!(-30), dec!(0), dec!(-30), false);
+        // Within the overdraft, the negative total isn't reported.
+        assert_eq!(
+            errors,
+            vec!["Error when handling transaction \"3\": Insufficient available funds"]
+        );
+    }
+
+    #[test]
+    fn test_negative_total_warning() {
+        let mut engine = Engine::new(EngineConfig {
+            dispute_policy: DisputePolicy::AllowNegative,
+            ..EngineConfig::default()
+        });
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(100.0))),
+            Transaction::new("withdrawal".into(), 1, 2, Some(dec!(80.0))),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("chargeback".into(), 1, 1, None),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        assert_eq!(
+            engine.warnings(),
+            ["Warning: total of client 1 went negative (-80) after transaction \"1\""]
+        );
+
+        let (accounts, errors) = engine.finish();
+        assert_account(&accounts[0], dec!(-80.0), dec!(0), dec!(-80.0), true);
+        assert!(errors.is_empty());
+
+        // An overdraft taking the total below zero warns too, once.
+        let mut engine = Engine::new(EngineConfig {
+            overdraft: dec!(50),
+            ..EngineConfig::default()
+        });
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("withdrawal".into(), 1, 2, Some(dec!(10.0))),
+            Transaction::new("withdrawal".into(), 1, 3, Some(dec!(20.0))),
+            Transaction::new("withdrawal".into(), 1, 4, Some(dec!(5.0))),
+        ];
+        for transaction in transactions {
+            engine.apply(transaction).unwrap();
+        }
+        assert_eq!(
+            engine.warnings(),
+            ["Warning: total of client 1 went negative (-20) after transaction \"3\""]
+        );
+    }
+
+    #[test]
+    fn test_errors_by_client() {
+        let mut engine = Engine::new(EngineConfig::default());
+        let transactions = vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+            Transaction::new("withdrawal".into(), 1, 2, Some(dec!(20.0))),
+            Transaction::new("deposit".into(), 2, 3, Some(dec!(5.0))),
+            Transaction::new("dispute".into(), 2, 9, None),
+            Transaction::new("resolve".into(), 1, 1, None),
+        ];
+        for transaction in transactions {
+            let _ = engine.apply(transaction);
+        }
+
+        let errors = engine.errors_by_client();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[&1].len(), 2);
+        assert!(matches!(
+            errors[&1][0],
+            EngineError::WithdrawalRejected { tx: 2, .. }
+        ));
+        assert!(matches!(
+            errors[&1][1],
+            EngineError::NotDisputed { tx: 1, .. }
+        ));
+        assert_eq!(errors[&2], vec![EngineError::UnknownTransaction { tx: 9 }]);
+        // The flat list keeps all of them in input order.
+        let (_, flat) = engine.finish();
+        assert_eq!(flat.len(), 3);
+    }
+
+    #[test]
+    fn test_output_order() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), 3, 1, Some(dec!(1.0))),
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(1.0))),
+            Transaction::new("deposit".into(), 2, 3, Some(dec!(1.0))),
+            Transaction::new("deposit".into(), 1, 4, Some(dec!(1.0))),
+        ];
+        let clients = |order| {
+            let config = EngineConfig {
+                order,
+                ..EngineConfig::default()
+            };
+            let (accounts, _) = process_transactions_from(vec![], transactions.clone(), &config);
+            accounts
+                .iter()
+                .map(|account| account.client)
+                .collect::<Vec<u16>>()
+        };
+
+        assert_eq!(clients(OutputOrder::ByClientId), vec![1, 2, 3]);
+        assert_eq!(clients(OutputOrder::FirstSeen), vec![3, 1, 2]);
+    }
+
+    #[test]
+    fn test_into_accounts() {
+        let transactions = vec![
+            Transaction::new("deposit".into(), 3, 1, Some(dec!(1.0))),
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(2.0))),
+            Transaction::new("withdrawal".into(), 3, 3, Some(dec!(0.5))),
+            Transaction::new("deposit".into(), 2, 4, Some(dec!(3.0))),
+        ];
+        let orders = vec![OutputOrder::ByClientId, OutputOrder::FirstSeen];
+        for order in orders {
+            let config = EngineConfig {
+                order,
+                ..EngineConfig::default()
+            };
+            let (expected, _) = process_transactions_from(vec![], transactions.clone(), &config);
+            let mut engine = Engine::new(config);
+            for transaction in transactions.clone() {
+                engine.apply(transaction).unwrap();
+            }
+
+            assert_eq!(engine.into_accounts().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn test_sort_by_sequence() {
+        let sequenced = |kind: &str, tx, amount, sequence| Transaction {
+            sequence,
+            ..Transaction::new(kind.into(), 1, tx, amount)
+        };
+        let mut transactions = vec![
+            sequenced("dispute", 1, None, Some(3)),
+            sequenced("deposit", 2, Some(dec!(5.0)), None),
+            sequenced("deposit", 1, Some(dec!(10.0)), Some(1)),
+            sequenced("deposit", 3, Some(dec!(1.0)), Some(2)),
+            sequenced("deposit", 4, Some(dec!(1.0)), None),
+        ];
+
+        sort_by_sequence(&mut transactions);
+
+        let txs: Vec<u32> = transactions
+            .iter()
+            .map(|transaction| transaction.tx)
+            .collect();
+        assert_eq!(txs, vec![1, 3, 1, 2, 4]);
+        let (accounts, errors) = process_transactions(transactions);
+        assert!(errors.is_empty());
+        assert_account(&accounts[0], dec!(7.0), dec!(10.0), dec!(17.0), false);
+    }
+
+    #[test]
+    fn test_allowlist_skips_other_clients() {
+        let allowlist: HashSet<u16> = vec![1, 2].into_iter().collect();
+        let (allowed, skipped) = retain_allowed(
+            vec![
+                Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+                Transaction::new("deposit".into(), 2, 2, Some(dec!(20.0))),
+                Transaction::new("deposit".into(), 3, 3, Some(dec!(30.0))),
+                Transaction::new("withdrawal".into(), 3, 4, Some(dec!(5.0))),
+            ],
+            &allowlist,
+        );
+
+        let (mut accounts, errors) = process_transactions(allowed);
+        accounts.sort_by_key(|account| account.client);
+
+        assert_eq!(skipped, 2);
+        assert_eq!(accounts.len(), 2);
+        assert_account(&accounts[0], dec!(10.0), dec!(0.0), dec!(10.0), false);
+        assert_account(&accounts[1], dec!(20.0), dec!(0.0), dec!(20.0), false);
+        assert_eq!(errors.len(), 0);
+    }
+
     fn assert_account(
         account: &Account,
         available: Decimal,
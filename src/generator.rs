@@ -0,0 +1,109 @@
+use crate::types::Transaction;
+use rust_decimal::Decimal;
+use std::io::{self, Write};
+
+/// Number of distinct clients generated transactions are spread over.
+const CLIENTS: u64 = 100;
+
+/// Small splitmix64 generator, so generated files don't depend on an external
+/// RNG's algorithm staying stable between versions.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+/// Builds `rows` synthetic transactions; the same seed always produces the
+/// same transactions. Disputes only reference earlier deposits of the same
+/// client, and resolves and chargebacks only open disputes.
+pub fn transactions(seed: u64, rows: usize) -> Vec<Transaction> {
+    let mut rng = Rng(seed);
+    let mut deposits: Vec<(u16, u32)> = Vec::new();
+    let mut disputes: Vec<(u16, u32)> = Vec::new();
+    let mut transactions = Vec::with_capacity(rows);
+    for id in 1..=rows as u32 {
+        let roll = rng.below(100);
+        let transaction = if roll < 10 && !deposits.is_empty() {
+            let (client, tx) = deposits.swap_remove(rng.below(deposits.len() as u64) as usize);
+            disputes.push((client, tx));
+            Transaction::new("dispute".into(), client, tx, None)
+        } else if roll < 17 && !disputes.is_empty() {
+            let (client, tx) = disputes.swap_remove(rng.below(disputes.len() as u64) as usize);
+            let kind = if rng.below(4) == 0 {
+                "chargeback"
+            } else {
+                "resolve"
+            };
+            Transaction::new(kind.into(), client, tx, None)
+        } else {
+            let client = (rng.below(CLIENTS) + 1) as u16;
+            let amount = Decimal::new(rng.below(10_000_000) as i64 + 1, 4);
+            if roll < 70 {
+                deposits.push((client, id));
+                Transaction::new("deposit".into(), client, id, Some(amount))
+            } else {
+                Transaction::new("withdrawal".into(), client, id, Some(amount))
+            }
+        };
+        transactions.push(transaction);
+    }
+    transactions
+}
+
+/// Writes `transactions(seed, rows)` as CSV.
+pub fn generate<W: Write>(out: &mut W, seed: u64, rows: usize) -> io::Result<()> {
+    writeln!(out, "type,client,tx,amount")?;
+    for transaction in transactions(seed, rows) {
+        let amount = transaction
+            .amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default();
+        writeln!(
+            out,
+            "{},{},{},{}",
+            transaction.transaction_type, transaction.client, transaction.tx, amount
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helpers::{process_reader, ParseConfig};
+
+    fn generated(seed: u64, rows: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        generate(&mut out, seed, rows).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_same_seed_is_byte_identical() {
+        assert_eq!(generated(7, 1000), generated(7, 1000));
+        assert_ne!(generated(7, 1000), generated(8, 1000));
+    }
+
+    #[test]
+    fn test_generated_csv_parses() {
+        let csv = generated(42, 500);
+
+        let (transactions, errors) = process_reader(&csv[..], &ParseConfig::default()).unwrap();
+
+        assert_eq!(transactions.len(), 500);
+        assert!(errors.is_empty());
+        assert!(transactions
+            .iter()
+            .any(|transaction| transaction.transaction_type == "dispute"));
+    }
+}
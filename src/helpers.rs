@@ -1,28 +1,1684 @@
-use crate::types::{Account, Transaction};
-use csv::{ReaderBuilder, Trim};
+use crate::engine::DEFAULT_PRECISION;
+use crate::types::{round_amount, Account, Transaction, MAX_AMOUNT_SCALE};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub fn process_csv(path: &str) -> Result<Vec<Transaction>, Box<dyn Error>> {
-    let mut reader = ReaderBuilder::new().trim(Trim::All).from_path(&path)?;
+pub fn is_supported_input(path: &str) -> bool {
+    path.ends_with(".csv")
+        || path.ends_with(".csv.zst")
+        || (cfg!(feature = "zip") && path.ends_with(".zip"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// One JSON transaction object per line.
+    Ndjson,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParseConfig {
+    pub format: InputFormat,
+    /// Abort on the first malformed row instead of skipping it.
+    pub strict: bool,
+    /// Stop reading after this many successfully parsed rows.
+    pub limit: Option<usize>,
+    /// Most decimal places accepted in an amount; rows with more are
+    /// malformed rather than silently rounded.
+    pub max_scale: u32,
+    /// Ticked for every row read, including the malformed ones skipped.
+    pub progress: Option<SharedProgress>,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        Self {
+            format: InputFormat::default(),
+            strict: false,
+            limit: None,
+            max_scale: MAX_AMOUNT_SCALE,
+            progress: None,
+        }
+    }
+}
+
+impl ParseConfig {
+    fn tick_progress(&self) {
+        if let Some(Ok(mut progress)) = self.progress.as_ref().map(|progress| progress.lock()) {
+            let _ = progress.tick();
+        }
+    }
+}
+
+pub fn process_csv(
+    path: &str,
+    config: &ParseConfig,
+) -> Result<(Vec<Transaction>, Vec<String>), Box<dyn Error>> {
+    let file = File::open(path)?;
+    #[cfg(feature = "zip")]
+    {
+        if path.ends_with(".zip") {
+            return process_zip(file, config);
+        }
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if path.ends_with(".zst") {
+            return process_stream(zstd::Decoder::new(file)?, config);
+        }
+    }
+    process_stream(file, config)
+}
+
+/// Like `process_csv`, for input already in memory, e.g. an uploaded file.
+pub fn process_csv_bytes(
+    bytes: &[u8],
+    config: &ParseConfig,
+) -> Result<(Vec<Transaction>, Vec<String>), Box<dyn Error>> {
+    process_stream(Cursor::new(bytes), config)
+}
+
+/// Processes CSV transactions held in a string with the default settings,
+/// returning the accounts as CSV. Neither touches the filesystem nor stdio, so
+/// it also runs in the browser (see the `wasm` feature).
+pub fn process_csv_string(input: &str) -> Result<String, Box<dyn Error>> {
+    let (transactions, _) = process_csv_bytes(input.as_bytes(), &ParseConfig::default())?;
+    let (accounts, errors) = crate::engine::process_transactions(transactions);
+    let mut out = Vec::new();
+    process_output(&mut out, accounts, errors, &OutputConfig::default())?;
+    Ok(String::from_utf8(out)?)
+}
+
+/// Parses every `.csv` entry of a zip archive in name order as one stream of
+/// transactions, so disputes may reference transactions of earlier files.
+/// Other entries are skipped, each with a `warning: ` line among the returned
+/// errors.
+#[cfg(feature = "zip")]
+pub fn process_zip<R: Read + std::io::Seek>(
+    input: R,
+    config: &ParseConfig,
+) -> Result<(Vec<Transaction>, Vec<String>), Box<dyn Error>> {
+    let mut archive = zip::ZipArchive::new(input)?;
+    let mut names: Vec<String> = archive.file_names().map(String::from).collect();
+    names.sort();
     let mut transactions: Vec<Transaction> = Vec::new();
+    let mut parse_errors: Vec<String> = Vec::new();
+    for name in names {
+        if name.ends_with('/') {
+            continue;
+        }
+        if !name.to_ascii_lowercase().ends_with(".csv") {
+            parse_errors.push(format!(
+                "warning: skipping non-CSV entry \"{}\" in zip archive",
+                name
+            ));
+            continue;
+        }
+        let remaining = ParseConfig {
+            limit: config.limit.map(|limit| limit - transactions.len()),
+            ..config.clone()
+        };
+        let (parsed, errors) = process_reader(archive.by_name(&name)?, &remaining)
+            .map_err(|err| format!("{}: {}", name, err))?;
+        transactions.extend(parsed);
+        parse_errors.extend(errors.into_iter().map(|err| format!("{}: {}", name, err)));
+        if config.limit == Some(transactions.len()) {
+            break;
+        }
+    }
+    Ok((transactions, parse_errors))
+}
+
+/// Parses transactions from any reader in the configured format, e.g. stdin.
+pub fn process_stream<R: Read>(
+    input: R,
+    config: &ParseConfig,
+) -> Result<(Vec<Transaction>, Vec<String>), Box<dyn Error>> {
+    match config.format {
+        InputFormat::Csv => process_reader(input, config),
+        InputFormat::Ndjson => process_ndjson(BufReader::new(input), config),
+    }
+}
+
+/// Parses transactions from CSV, returning them along with an error for each
+/// malformed row that was skipped, such as one with more or fewer columns than
+/// the header. With `strict` set the first malformed row
+/// aborts parsing instead. With a `limit` the rest of the input is left unread
+/// once that many transactions were parsed; skipped rows don't count.
+///
+/// A header row in the middle of the input, as left by concatenating CSV
+/// files, is skipped and its column order used for the rows that follow.
+/// Empty input, without even a header, parses as no transactions.
+pub fn process_reader<R: Read>(
+    input: R,
+    config: &ParseConfig,
+) -> Result<(Vec<Transaction>, Vec<String>), Box<dyn Error>> {
+    // Row lengths are checked against the current header below, so that
+    // concatenated files may differ in their optional columns. Rows are
+    // trimmed there too, once a blank amount was told from a missing one.
+    let mut reader = ReaderBuilder::new().flexible(true).from_reader(input);
+    let mut headers = normalize_headers(reader.headers()?);
+    if headers.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    check_columns(&headers)?;
+    let mut transactions: Vec<Transaction> = Vec::new();
+    let mut parse_errors: Vec<String> = Vec::new();
+    let mut record = StringRecord::new();
+    while config.limit != Some(transactions.len()) {
+        let read = reader.read_record(&mut record);
+        let blank = blank_amount(&headers, &record);
+        record.trim();
+        let result: Result<Transaction, Box<dyn Error>> = match read {
+            Ok(false) => break,
+            Ok(true) if is_header(&record) => {
+                headers = normalize_headers(&record);
+                continue;
+            }
+            Ok(true) if record.len() != headers.len() => Err(format!(
+                "expected {} columns ({}), found {}",
+                headers.len(),
+                headers.iter().collect::<Vec<_>>().join(", "),
+                record.len()
+            )
+            .into()),
+            Ok(true) if blank.is_some() => {
+                Err(format!("invalid amount '{}'", blank.unwrap_or_default()).into())
+            }
+            Ok(true) => record
+                .deserialize::<Transaction>(Some(&headers))
+                .map_err(Into::into)
+                .and_then(|transaction| exact_amount(transaction, &headers, &record))
+                .and_then(|transaction| check_scale(transaction, config.max_scale)),
+            Err(err) if err.is_io_error() => return Err(err.into()),
+            Err(err) => Err(err.into()),
+        };
+        config.tick_progress();
+        match result {
+            Ok(transaction) => transactions.push(transaction),
+            Err(err) if config.strict => return Err(err),
+            Err(err) => {
+                let line = err
+                    .downcast_ref::<csv::Error>()
+                    .and_then(csv::Error::position)
+                    .or_else(|| record.position())
+                    .map_or(0, |position| position.line());
+                parse_errors.push(format!("Skipping malformed row at line {}: {}", line, err));
+            }
+        }
+    }
+    Ok((transactions, parse_errors))
+}
+
+/// Takes the amount from its column's text: the csv crate hands amounts with
+/// a fraction to serde as `f64`, whose 15 to 17 significant digits would
+/// round e.g. `1234567890.12345678`.
+fn exact_amount(
+    mut transaction: Transaction,
+    headers: &StringRecord,
+    record: &StringRecord,
+) -> Result<Transaction, Box<dyn Error>> {
+    let text = headers
+        .iter()
+        .position(|header| header == "amount")
+        .and_then(|index| record.get(index));
+    if let (Some(_), Some(text)) = (transaction.amount, text) {
+        let amount = Decimal::from_str(text).or_else(|_| Decimal::from_scientific(text))?;
+        transaction.amount = Some(amount);
+    }
+    Ok(transaction)
+}
+
+/// The amount field of `record` if it holds only whitespace: present, unlike
+/// an empty field, so it's a malformed amount rather than a missing one.
+fn blank_amount(headers: &StringRecord, record: &StringRecord) -> Option<String> {
+    headers
+        .iter()
+        .position(|header| header == "amount")
+        .and_then(|index| record.get(index))
+        .filter(|field| !field.is_empty() && field.trim().is_empty())
+        .map(String::from)
+}
+
+fn check_scale(transaction: Transaction, max_scale: u32) -> Result<Transaction, Box<dyn Error>> {
+    match transaction.amount {
+        Some(amount) if amount.normalize().scale() > max_scale => Err(format!(
+            "amount {} has more than {} decimal places",
+            amount, max_scale
+        )
+        .into()),
+        _ => Ok(transaction),
+    }
+}
+
+/// Header names as `Transaction` expects them, so that e.g. `Client` and
+/// ` AMOUNT ` name the same columns as `client` and `amount`.
+fn normalize_headers(headers: &StringRecord) -> StringRecord {
+    headers.iter().map(normalize_header).collect()
+}
+
+fn normalize_header(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+fn missing_column(headers: &StringRecord) -> Option<&'static str> {
+    Transaction::COLUMNS
+        .iter()
+        .find(|column| !headers.iter().any(|h| normalize_header(h) == **column))
+        .copied()
+}
+
+fn check_columns(headers: &StringRecord) -> Result<(), Box<dyn Error>> {
+    match missing_column(headers) {
+        Some(column) => Err(format!("missing required column \"{}\"", column).into()),
+        None => Ok(()),
+    }
+}
+
+/// A row naming every required column, e.g. the header of a concatenated file.
+fn is_header(record: &StringRecord) -> bool {
+    missing_column(record).is_none()
+}
+
+/// Like `process_reader`, but for one JSON transaction object per line. Blank
+/// lines are ignored.
+pub fn process_ndjson<R: BufRead>(
+    input: R,
+    config: &ParseConfig,
+) -> Result<(Vec<Transaction>, Vec<String>), Box<dyn Error>> {
+    let mut transactions: Vec<Transaction> = Vec::new();
+    let mut parse_errors: Vec<String> = Vec::new();
+    let mut lines = input.lines().enumerate();
+    while config.limit != Some(transactions.len()) {
+        let (index, line) = match lines.next() {
+            Some((index, line)) => (index, line?),
+            None => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = parse_json_line(&line)
+            .and_then(|transaction| check_scale(transaction, config.max_scale));
+        config.tick_progress();
+        match result {
+            Ok(transaction) => transactions.push(transaction),
+            Err(err) if config.strict => return Err(err),
+            Err(err) => parse_errors.push(format!(
+                "Skipping malformed row at line {}: {}",
+                index + 1,
+                err
+            )),
+        }
+    }
+    Ok((transactions, parse_errors))
+}
+
+/// Reads one NDJSON transaction, normalizing its keys like CSV headers.
+fn parse_json_line(line: &str) -> Result<Transaction, Box<dyn Error>> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(line)?;
+    let object = object
+        .into_iter()
+        .map(|(key, value)| (normalize_header(&key), value))
+        .collect();
+    Ok(serde_json::from_value(serde_json::Value::Object(object))?)
+}
+
+pub fn load_allowlist(path: &str) -> Result<HashSet<u16>, Box<dyn Error>> {
+    let mut allowlist = HashSet::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if !line.is_empty() {
+            allowlist.insert(line.parse::<u16>()?);
+        }
+    }
+    Ok(allowlist)
+}
+
+pub fn load_snapshot(path: &str) -> Result<Vec<Account>, Box<dyn Error>> {
+    read_snapshot(File::open(path)?)
+}
+
+pub fn read_snapshot<R: Read>(input: R) -> Result<Vec<Account>, Box<dyn Error>> {
+    let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(input);
+    let mut accounts: Vec<Account> = Vec::new();
     for result in reader.deserialize() {
-        let record: Transaction = result?;
-        transactions.push(record);
+        let account: Account = result?;
+        if !account.is_consistent() {
+            return Err(format!(
+                "snapshot account {} has inconsistent balances",
+                account.client
+            )
+            .into());
+        }
+        accounts.push(account);
     }
-    Ok(transactions)
+    Ok(accounts)
+}
+
+/// Keeps only the accounts that are new or differ from their snapshot entry.
+pub fn changed_accounts(accounts: Vec<Account>, snapshot: &[Account]) -> Vec<Account> {
+    let previous: HashMap<(u16, Option<&str>), &Account> = snapshot
+        .iter()
+        .map(|account| ((account.client, account.currency.as_deref()), account))
+        .collect();
+    accounts
+        .into_iter()
+        .filter(|account| {
+            previous.get(&(account.client, account.currency.as_deref())) != Some(&account)
+        })
+        .collect()
+}
+
+/// Keeps only the accounts of the given clients, also returning the requested
+/// client ids that have no account.
+pub fn select_clients(accounts: Vec<Account>, clients: &[u16]) -> (Vec<Account>, Vec<u16>) {
+    let selected: Vec<Account> = accounts
+        .into_iter()
+        .filter(|account| clients.contains(&account.client))
+        .collect();
+    let missing = clients
+        .iter()
+        .filter(|client| !selected.iter().any(|account| account.client == **client))
+        .copied()
+        .collect();
+    (selected, missing)
 }
 
-pub fn process_output(processed_txs: Vec<Account>, tx_errs: Vec<String>, output_tx_errs: bool) {
-    if output_tx_errs {
+/// Drops accounts with nothing in them, e.g. fully withdrawn ones: zero
+/// total, nothing held and not locked. Held funds are kept even with a zero
+/// total, as a dispute may have taken available funds negative.
+pub fn omit_zero_accounts(accounts: Vec<Account>) -> Vec<Account> {
+    accounts
+        .into_iter()
+        .filter(|account| !account.total.is_zero() || !account.held.is_zero() || account.locked)
+        .collect()
+}
+
+/// Machine-readable run summary for `--report json`.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Report {
+    /// Transactions fed to the engine.
+    pub processed: usize,
+    /// Malformed rows and rejected transactions.
+    pub errors: usize,
+    pub accounts: usize,
+    pub locked: usize,
+}
+
+impl Report {
+    pub fn new(processed: usize, errors: usize, accounts: &[Account]) -> Self {
+        Self {
+            processed,
+            errors,
+            accounts: accounts.len(),
+            locked: accounts.iter().filter(|account| account.locked).count(),
+        }
+    }
+}
+
+/// Periodic `progress: <rows> rows (<rate> rows/s)` lines for `--progress`,
+/// written every `every` rows but no more often than once per `interval`.
+pub struct Progress<W: Write> {
+    out: W,
+    every: usize,
+    interval: Duration,
+    rows: usize,
+    started: Instant,
+    last: Instant,
+}
+
+/// A `Progress` the parsers share, e.g. across the entries of a zip archive.
+pub type SharedProgress = Arc<Mutex<Progress<Box<dyn Write + Send>>>>;
+
+impl<W: Write> fmt::Debug for Progress<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("rows", &self.rows)
+            .finish()
+    }
+}
+
+impl<W: Write> Progress<W> {
+    pub fn new(out: W, every: usize, interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            out,
+            every: every.max(1),
+            interval,
+            rows: 0,
+            started: now,
+            last: now,
+        }
+    }
+
+    /// Counts a row read, printing a line when one is due.
+    pub fn tick(&mut self) -> io::Result<()> {
+        self.rows += 1;
+        if self.rows.is_multiple_of(self.every) && self.last.elapsed() >= self.interval {
+            self.last = Instant::now();
+            writeln!(
+                self.out,
+                "progress: {} rows ({} rows/s)",
+                self.rows,
+                self.rate()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Prints the final row count and overall rate.
+    pub fn finish(&mut self) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "progress: {} rows done in {:.2}s ({} rows/s)",
+            self.rows,
+            self.started.elapsed().as_secs_f64(),
+            self.rate()
+        )
+    }
+
+    fn rate(&self) -> u64 {
+        (self.rows as f64 / self.started.elapsed().as_secs_f64().max(1e-9)) as u64
+    }
+}
+
+/// JSON view of an account for consumers expecting camelCase balance names,
+/// leaving `Account`'s own serialization (CSV output, saved state) unchanged.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputAccount {
+    pub client: u16,
+    pub available_balance: Decimal,
+    pub held_balance: Decimal,
+    pub total_balance: Decimal,
+    /// Left out of the JSON when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locked: Option<bool>,
+    /// Why the account is locked, see `Account::lock_reason`; left out of
+    /// the JSON when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<String>,
+    /// The open disputes making up `held_balance`, see
+    /// `with_held_transactions`; left out of the JSON when empty.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub held_transactions: Vec<HeldTransaction>,
+}
+
+/// A disputed transaction and the amount it holds.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HeldTransaction {
+    pub tx: u32,
+    pub amount: Decimal,
+}
+
+impl OutputAccount {
+    pub fn new(account: &Account, include_locked: bool) -> Self {
+        Self {
+            client: account.client,
+            available_balance: account.available,
+            held_balance: account.held,
+            total_balance: account.total,
+            locked: Some(account.locked).filter(|_| include_locked),
+            lock_reason: account.lock_reason.clone(),
+            held_transactions: Vec::new(),
+        }
+    }
+
+    /// Adds the breakdown of the held balance, e.g. from
+    /// `Engine::held_breakdown`, for auditing which disputes hold the funds.
+    pub fn with_held_transactions(mut self, held: Vec<(u32, Decimal)>) -> Self {
+        self.held_transactions = held
+            .into_iter()
+            .map(|(tx, amount)| HeldTransaction { tx, amount })
+            .collect();
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    /// Print transaction errors ahead of the accounts.
+    pub output_tx_errs: bool,
+    /// Append the extra audit columns (`first_tx`, `resolved_count`,
+    /// `chargeback_count`, `lifetime_deposited`, `lifetime_withdrawn` and
+    /// `last_modified`).
+    pub verbose: bool,
+    /// Decimal places all amounts are rounded to.
+    pub precision: u32,
+    /// Fixed decimal places for the available column, overriding `precision`.
+    pub available_precision: Option<u32>,
+    /// Fixed decimal places for the held column, overriding `precision`.
+    pub held_precision: Option<u32>,
+    /// Append a `# totals` comment row summing the printed balances.
+    pub totals_row: bool,
+    /// Print negative available and total balances smaller than one unit of
+    /// their printed precision (rounding dust) as zero.
+    pub clamp_negative_dust: bool,
+    /// Add a `currency` column after `client`, for multi-currency input.
+    pub currency: bool,
+    /// Zero-pad the balance columns to this width, with a fixed number of
+    /// decimal places (the column's precision), for fixed-width consumers.
+    pub pad_width: Option<usize>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            output_tx_errs: false,
+            verbose: false,
+            precision: DEFAULT_PRECISION,
+            available_precision: None,
+            held_precision: None,
+            totals_row: false,
+            clamp_negative_dust: false,
+            currency: false,
+            pad_width: None,
+        }
+    }
+}
+
+pub fn process_output<W: Write>(
+    out: &mut W,
+    processed_txs: Vec<Account>,
+    tx_errs: Vec<String>,
+    config: &OutputConfig,
+) -> io::Result<()> {
+    if config.output_tx_errs {
         for err in tx_errs {
-            println!("{}", err)
+            writeln!(out, "{}", err)?;
         }
     }
-    println!("client,available,held,total,locked");
+    write_header(out, config)?;
+    let zero = Decimal::from(0);
+    let mut totals = (zero, zero, zero);
     for tx in processed_txs {
-        println!(
-            "{},{},{},{},{}",
-            tx.client, tx.available, tx.held, tx.total, tx.locked
+        let (available, held, total) = balances(&tx, config);
+        totals = (totals.0 + available, totals.1 + held, totals.2 + total);
+        write_row(out, &tx, config)?;
+    }
+    if config.totals_row {
+        // Commented so consumers parsing client ids skip it.
+        writeln!(out, "# totals,{},{},{}", totals.0, totals.1, totals.2)?;
+    }
+    Ok(())
+}
+
+/// Retries writes that fail with a transient error (interrupted, would block
+/// or timed out) up to `retries` times each, waiting 10ms, then twice as long
+/// after every further failure, e.g. for a flaky network sink. Other errors
+/// and running out of retries fail as usual.
+pub struct RetryWriter<W: Write> {
+    inner: W,
+    retries: u32,
+    delay: Duration,
+}
+
+impl<W: Write> RetryWriter<W> {
+    pub fn new(inner: W, retries: u32) -> Self {
+        Self {
+            inner,
+            retries,
+            delay: Duration::from_millis(10),
+        }
+    }
+
+    fn retry<T>(&mut self, mut op: impl FnMut(&mut W) -> io::Result<T>) -> io::Result<T> {
+        let mut delay = self.delay;
+        let mut attempt = 0;
+        loop {
+            match op(&mut self.inner) {
+                Err(err) if attempt < self.retries && is_transient(&err) => {
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for RetryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(Write::flush)
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Writes each account to its own `client_<id>.csv` file in `dir` (or
+/// `client_<id>_<currency>.csv`), creating the directory if needed.
+pub fn write_split_output(
+    dir: &Path,
+    accounts: &[Account],
+    config: &OutputConfig,
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for account in accounts {
+        let path = match &account.currency {
+            Some(currency) => dir.join(format!("client_{}_{}.csv", account.client, currency)),
+            None => dir.join(format!("client_{}.csv", account.client)),
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        write_header(&mut out, config)?;
+        write_row(&mut out, account, config)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+pub fn write_header<W: Write>(out: &mut W, config: &OutputConfig) -> io::Result<()> {
+    write!(out, "client")?;
+    if config.currency {
+        write!(out, ",currency")?;
+    }
+    write!(out, ",available,held,total,locked")?;
+    if config.verbose {
+        write!(
+            out,
+            ",first_tx,resolved_count,chargeback_count,lifetime_deposited,lifetime_withdrawn,\
+             last_modified"
+        )?;
+    }
+    writeln!(out)
+}
+
+pub fn write_row<W: Write>(
+    out: &mut W,
+    account: &Account,
+    config: &OutputConfig,
+) -> io::Result<()> {
+    let (available, held, total) = balances(account, config);
+    write!(out, "{}", account.client)?;
+    if config.currency {
+        write!(out, ",{}", account.currency.as_deref().unwrap_or_default())?;
+    }
+    let available_places = config.available_precision.unwrap_or(config.precision);
+    let held_places = config.held_precision.unwrap_or(config.precision);
+    write!(
+        out,
+        ",{},{},{},{}",
+        pad(available, available_places, config),
+        pad(held, held_places, config),
+        pad(total, available_places.max(held_places), config),
+        account.locked
+    )?;
+    if config.verbose {
+        let first_tx = account
+            .first_tx
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+        let last_modified = account
+            .last_modified
+            .map(|index| index.to_string())
+            .unwrap_or_default();
+        write!(
+            out,
+            ",{},{},{},{},{},{}",
+            first_tx,
+            account.resolved_count,
+            account.chargeback_count,
+            round_amount(account.lifetime_deposited, config.precision),
+            round_amount(account.lifetime_withdrawn, config.precision),
+            last_modified
+        )?;
+    }
+    writeln!(out)
+}
+
+/// The amount zero-padded to `pad_width` with `places` decimal places, if set.
+fn pad(amount: Decimal, places: u32, config: &OutputConfig) -> String {
+    match config.pad_width {
+        Some(width) => format!(
+            "{:0width$.places$}",
+            amount,
+            width = width,
+            places = places as usize
+        ),
+        None => amount.to_string(),
+    }
+}
+
+/// Available, held and total balances as printed.
+fn balances(account: &Account, config: &OutputConfig) -> (Decimal, Decimal, Decimal) {
+    let clamp = |amount: Decimal, precision: u32| {
+        if config.clamp_negative_dust {
+            clamp_dust(amount, precision)
+        } else {
+            amount
+        }
+    };
+    let available = clamp(
+        round_to(
+            account.available,
+            config.available_precision,
+            config.precision,
+        ),
+        config.available_precision.unwrap_or(config.precision),
+    );
+    let held = round_to(account.held, config.held_precision, config.precision);
+    // Keep total consistent with the printed columns once either is rescaled.
+    let total = match (config.available_precision, config.held_precision) {
+        (None, None) => clamp(
+            round_amount(account.total, config.precision),
+            config.precision,
+        ),
+        _ => available + held,
+    };
+    (available, held, total)
+}
+
+/// Zero for negative amounts below one unit at `precision` decimal places,
+/// keeping the scale.
+fn clamp_dust(amount: Decimal, precision: u32) -> Decimal {
+    if amount.is_sign_negative() && -amount < Decimal::new(1, precision) {
+        Decimal::new(0, amount.scale())
+    } else {
+        amount
+    }
+}
+
+fn round_to(amount: Decimal, fixed: Option<u32>, precision: u32) -> Decimal {
+    match fixed {
+        Some(fixed) => {
+            let mut rounded = amount.round_dp(fixed);
+            rounded.rescale(fixed);
+            rounded
+        }
+        None => round_amount(amount, precision),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::{process_transactions_from, EngineConfig};
+    use rust_decimal_macros::dec;
+
+    const FIXTURE: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+withdrawal,1,3,0.5
+dispute,2,2,0.0
+";
+
+    #[test]
+    fn test_zstd_matches_plaintext() {
+        let compressed = zstd::encode_all(FIXTURE.as_bytes(), 0).unwrap();
+
+        let (plain, _) = process_reader(FIXTURE.as_bytes(), &ParseConfig::default()).unwrap();
+        let (decoded, errors) = process_reader(
+            zstd::Decoder::new(&compressed[..]).unwrap(),
+            &ParseConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(decoded, plain);
+        assert_eq!(decoded.len(), 4);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_capitalized_headers() {
+        let input = FIXTURE.replacen("type,client,tx,amount", "Type,Client,Tx,Amount", 1);
+
+        let (transactions, _) = process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert_eq!(
+            transactions,
+            process_reader(FIXTURE.as_bytes(), &ParseConfig::default())
+                .unwrap()
+                .0
+        );
+    }
+
+    #[test]
+    fn test_mixed_case_padded_headers() {
+        let header = " tYpE , cLiEnt,TX , Amount ";
+        let input = FIXTURE.replacen("type,client,tx,amount", header, 1);
+        let input = format!("{}{}\ndeposit,9,99,1.0\n", input, header);
+
+        let (transactions, errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut expected = process_reader(FIXTURE.as_bytes(), &ParseConfig::default())
+            .unwrap()
+            .0;
+        expected.push(Transaction::new(
+            "deposit".to_string(),
+            9,
+            99,
+            Some(dec!(1.0)),
+        ));
+        assert_eq!(transactions, expected);
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let inputs = vec!["", "type,client,tx,amount\n"];
+        for input in inputs {
+            let (transactions, errors) =
+                process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+            assert!(transactions.is_empty());
+            assert!(errors.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_csv_bytes() {
+        let bytes = b"type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,2,2,0.5\n";
+
+        let (transactions, errors) = process_csv_bytes(bytes, &ParseConfig::default()).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(
+            transactions,
+            vec![
+                Transaction::new("deposit".to_string(), 1, 1, Some(dec!(1.5))),
+                Transaction::new("withdrawal".to_string(), 2, 2, Some(dec!(0.5))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_csv_string() {
+        let input = "type,client,tx,amount\ndeposit,2,1,3.0\ndeposit,1,2,1.5\nwithdrawal,2,3,1.0\n";
+
+        assert_eq!(
+            process_csv_string(input).unwrap(),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,2,0,2,false\n"
+        );
+    }
+
+    #[test]
+    fn test_missing_amount_column() {
+        let input = "type,client,tx\ndeposit,1,1\n";
+
+        let err = process_reader(input.as_bytes(), &ParseConfig::default()).unwrap_err();
+
+        assert_eq!(err.to_string(), "missing required column \"amount\"");
+    }
+
+    #[test]
+    fn test_skip_malformed_row() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+this is garbage
+deposit,1,2,2.0
+";
+
+        let (transactions, errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[1].tx, 2);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("line 3"));
+    }
+
+    #[test]
+    fn test_wrong_column_count() {
+        let input = "type,client,tx,amount
+deposit,1,1
+deposit,1,2,2.0,3
+deposit,1,3,3.0
+";
+
+        let (transactions, errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].tx, 3);
+        assert_eq!(
+            errors,
+            vec![
+                "Skipping malformed row at line 2: expected 4 columns (type, client, tx, amount), found 3",
+                "Skipping malformed row at line 3: expected 4 columns (type, client, tx, amount), found 5",
+            ]
+        );
+        let err = process_reader(
+            input.as_bytes(),
+            &ParseConfig {
+                strict: true,
+                ..ParseConfig::default()
+            },
         )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected 4 columns (type, client, tx, amount), found 3"
+        );
+    }
+
+    #[test]
+    fn test_invalid_amounts() {
+        let input = "type,client,tx,amount
+deposit,1,1,NaN
+deposit,1,2,\"1,000\"
+deposit,1,3,inf
+deposit,1,4,   
+deposit,1,5,2.5
+";
+
+        let (transactions, errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert_eq!(
+            errors
+                .iter()
+                .map(|err| err.rsplit(": ").next().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "invalid amount 'NaN'",
+                "invalid amount '1,000'",
+                "invalid amount 'inf'",
+                "invalid amount '   '",
+            ]
+        );
+        assert!(errors[1].starts_with("Skipping malformed row at line 3:"));
+        assert_eq!(
+            errors[3],
+            "Skipping malformed row at line 5: invalid amount '   '"
+        );
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].amount, Some(dec!(2.5)));
+    }
+
+    #[test]
+    fn test_out_of_range_ids() {
+        let input = "type,client,tx,amount
+deposit,70000,1,1.0
+deposit,1,5000000000,1.0
+deposit,1,3,1.0
+";
+
+        let (transactions, errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("line 2"));
+        assert!(errors[0].contains("client id 70000 out of range (max 65535)"));
+        assert!(errors[1].contains("line 3"));
+        assert!(errors[1].contains("tx id 5000000000 out of range (max 4294967295)"));
+        assert!(process_reader(
+            input.as_bytes(),
+            &ParseConfig {
+                strict: true,
+                ..ParseConfig::default()
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_strict_aborts_on_malformed_row() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,one,2,2.0
+";
+
+        assert!(process_reader(
+            input.as_bytes(),
+            &ParseConfig {
+                strict: true,
+                ..ParseConfig::default()
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ndjson_matches_csv() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+{"type": "deposit", "client": 2, "tx": 2, "amount": "2.0"}
+{"type": "dispute", "client": 2, "tx": 2}
+"#;
+        let csv = "type,client,tx,amount
+deposit,1,1,1.5
+deposit,2,2,2.0
+dispute,2,2,
+";
+
+        let (transactions, errors) =
+            process_ndjson(input.as_bytes(), &ParseConfig::default()).unwrap();
+        let (expected, _) = process_reader(csv.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(transactions, expected);
+        assert_eq!(
+            crate::engine::process_transactions(transactions),
+            crate::engine::process_transactions(expected)
+        );
+    }
+
+    #[test]
+    fn test_ndjson_malformed_line() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+{"type": "deposit", "client": 1
+{"type": "deposit", "client": 1, "tx": 3, "amount": 1.0}
+"#;
+
+        let (transactions, errors) =
+            process_ndjson(input.as_bytes(), &ParseConfig::default()).unwrap();
+
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Skipping malformed row at line 2: "));
+        let strict = ParseConfig {
+            strict: true,
+            ..ParseConfig::default()
+        };
+        assert!(process_ndjson(input.as_bytes(), &strict).is_err());
+    }
+
+    #[test]
+    fn test_concatenated_csvs() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+client,tx,type,amount
+2,2,deposit,5.0
+1,1,resolve,
+";
+
+        let (transactions, errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+        let (accounts, tx_errors) = crate::engine::process_transactions(transactions);
+
+        assert!(errors.is_empty());
+        assert!(tx_errors.is_empty());
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(1, dec!(10.0), dec!(0), false),
+                Account::new(2, dec!(5.0), dec!(0), false),
+            ]
+        );
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn test_zip_archive() {
+        use std::io::Cursor;
+
+        let mut archive = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let entries = [
+            (
+                "10.csv",
+                "type,client,tx,amount\ndispute,1,1,\nresolve,1,1,\n",
+            ),
+            ("readme.txt", "not transactions"),
+            (
+                "09.csv",
+                "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n",
+            ),
+        ];
+        for (name, contents) in entries.iter() {
+            archive
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            archive.write_all(contents.as_bytes()).unwrap();
+        }
+        let zipped = archive.finish().unwrap().into_inner();
+
+        let (transactions, errors) =
+            process_zip(Cursor::new(zipped), &ParseConfig::default()).unwrap();
+        let (accounts, tx_errors) = crate::engine::process_transactions(transactions);
+
+        assert_eq!(
+            errors,
+            vec!["warning: skipping non-CSV entry \"readme.txt\" in zip archive"]
+        );
+        assert!(tx_errors.is_empty());
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(1, dec!(10.0), dec!(0), false),
+                Account::new(2, dec!(5.0), dec!(0), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut input = String::from("type,client,tx,amount\nnot-a-row\n");
+        for tx in 1..=100 {
+            input.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        let config = ParseConfig {
+            limit: Some(10),
+            ..ParseConfig::default()
+        };
+
+        let (transactions, errors) = process_reader(input.as_bytes(), &config).unwrap();
+
+        assert_eq!(transactions.len(), 10);
+        assert_eq!(transactions.last().unwrap().tx, 10);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn test_changed_only() {
+        let snapshot = read_snapshot(
+            "client,available,held,total,locked
+1,10.0,0,10.0,false
+2,20.0,0,20.0,false
+3,30.0,0,30.0,false
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let transactions = vec![Transaction::new("withdrawal".into(), 2, 1, Some(dec!(5.0)))];
+
+        let (accounts, errors) =
+            process_transactions_from(snapshot.clone(), transactions, &EngineConfig::default());
+        let changed = changed_accounts(accounts, &snapshot);
+
+        assert_eq!(changed, vec![Account::new(2, dec!(15.0), dec!(0), false)]);
+        assert_eq!(errors.len(), 0);
+    }
+
+    #[test]
+    fn test_inconsistent_snapshot() {
+        let snapshot = read_snapshot(
+            "client,available,held,total,locked
+1,10.0,0,12.0,false
+"
+            .as_bytes(),
+        );
+
+        assert_eq!(
+            snapshot.unwrap_err().to_string(),
+            "snapshot account 1 has inconsistent balances"
+        );
+    }
+
+    #[test]
+    fn test_amount_scale() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.1234
+deposit,1,2,1.12345
+deposit,1,3,1.10000
+";
+
+        let (transactions, errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+        let (accounts, _) = crate::engine::process_transactions(transactions);
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("amount 1.12345 has more than 4 decimal places"));
+        assert_eq!(accounts[0].available, dec!(2.2234));
+        assert!(accounts[0].available.normalize().scale() <= 4);
+    }
+
+    #[test]
+    fn test_eight_decimal_amounts() {
+        let input = "type,client,tx,amount
+deposit,1,1,1234567890.12345678
+deposit,1,2,0.00000001
+deposit,1,3,0.123456789
+";
+        let config = ParseConfig {
+            max_scale: 8,
+            ..ParseConfig::default()
+        };
+
+        let (transactions, errors) = process_reader(input.as_bytes(), &config).unwrap();
+
+        assert_eq!(transactions[0].amount, Some(dec!(1234567890.12345678)));
+        assert_eq!(transactions[1].amount, Some(dec!(0.00000001)));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("amount 0.123456789 has more than 8 decimal places"));
+    }
+
+    #[test]
+    fn test_verbose_output() {
+        let (accounts, _) = crate::engine::process_transactions(vec![
+            Transaction::new("deposit".into(), 1, 4, Some(dec!(1.5))),
+            Transaction::new("deposit".into(), 1, 9, Some(dec!(1.0))),
+            Transaction::new("withdrawal".into(), 1, 10, Some(dec!(0.5))),
+        ]);
+        let config = OutputConfig {
+            verbose: true,
+            ..OutputConfig::default()
+        };
+        let mut out = Vec::new();
+
+        process_output(&mut out, accounts, vec![], &config).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked,first_tx,resolved_count,chargeback_count,\
+             lifetime_deposited,lifetime_withdrawn,last_modified\n\
+             1,2.0,0,2.0,false,4,0,0,2.5,0.5,3\n"
+        );
+    }
+
+    #[test]
+    fn test_select_clients() {
+        let (accounts, _) = crate::engine::process_transactions(vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(1.0))),
+            Transaction::new("deposit".into(), 2, 2, Some(dec!(2.0))),
+            Transaction::new("deposit".into(), 3, 3, Some(dec!(3.0))),
+        ]);
+
+        let (selected, missing) = select_clients(accounts, &[2, 7]);
+
+        assert_eq!(selected, vec![Account::new(2, dec!(2.0), dec!(0), false)]);
+        assert_eq!(missing, vec![7]);
+    }
+
+    #[test]
+    fn test_available_and_held_precision() {
+        let accounts = vec![Account::new(1, dec!(1.23456), dec!(2.5), false)];
+        let config = OutputConfig {
+            available_precision: Some(2),
+            held_precision: Some(4),
+            ..OutputConfig::default()
+        };
+        let mut out = Vec::new();
+
+        process_output(&mut out, accounts, vec![], &config).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,1.23,2.5000,3.7300,false\n"
+        );
+    }
+
+    #[test]
+    fn test_output_precision() {
+        let accounts = vec![Account::new(1, dec!(1.23456), dec!(0), false)];
+        let output_at = |precision| {
+            let config = OutputConfig {
+                precision,
+                ..OutputConfig::default()
+            };
+            let mut out = Vec::new();
+            process_output(&mut out, accounts.clone(), vec![], &config).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            output_at(4),
+            "client,available,held,total,locked\n1,1.2346,0,1.2346,false\n"
+        );
+        assert_eq!(
+            output_at(2),
+            "client,available,held,total,locked\n1,1.23,0,1.23,false\n"
+        );
+    }
+
+    #[test]
+    fn test_pad_width() {
+        let accounts = vec![
+            Account::new(1, dec!(1.5), dec!(0), false),
+            Account::new(2, dec!(-12.25), dec!(20.125), true),
+        ];
+        let config = OutputConfig {
+            pad_width: Some(12),
+            ..OutputConfig::default()
+        };
+        let mut out = Vec::new();
+
+        process_output(&mut out, accounts, vec![], &config).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+             1,0000001.5000,0000000.0000,0000001.5000,false\n\
+             2,-000012.2500,0000020.1250,0000007.8750,true\n"
+        );
+    }
+
+    #[test]
+    fn test_split_output() {
+        let dir = std::env::temp_dir().join(format!(
+            "toy-transactions-engine-{}-split",
+            std::process::id()
+        ));
+        let accounts = vec![
+            Account::new(1, dec!(1.5), dec!(0), false),
+            Account::new(2, dec!(2.0), dec!(1.0), false),
+            Account::new(3, dec!(0), dec!(0), true),
+        ];
+
+        write_split_output(&dir, &accounts, &OutputConfig::default()).unwrap();
+
+        let read =
+            |client: u16| fs::read_to_string(dir.join(format!("client_{}.csv", client))).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        assert_eq!(
+            read(1),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+        assert_eq!(
+            read(2),
+            "client,available,held,total,locked\n2,2.0,1.0,3.0,false\n"
+        );
+        assert_eq!(
+            read(3),
+            "client,available,held,total,locked\n3,0,0,0,true\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_clamp_negative_dust() {
+        let accounts = vec![
+            Account::new(1, dec!(-0.00005), dec!(0), false),
+            Account::new(2, dec!(-0.5), dec!(0), false),
+            // What rounding leaves of e.g. 1.00001 - 1.00001 negated.
+            Account::new(3, -dec!(0.00000000), dec!(0), false),
+        ];
+        let output = |precision, clamp_negative_dust| {
+            let config = OutputConfig {
+                precision,
+                clamp_negative_dust,
+                ..OutputConfig::default()
+            };
+            let mut out = Vec::new();
+            process_output(&mut out, accounts.clone(), vec![], &config).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            output(8, false),
+            "client,available,held,total,locked\n1,-0.00005,0,-0.00005,false\n2,-0.5,0,-0.5,false\n3,-0.00000000,0,0,false\n"
+        );
+        // Only what prints as zero at the precision is dust: -0.00005 is a
+        // real balance at 8 places.
+        assert_eq!(
+            output(8, true),
+            "client,available,held,total,locked\n1,-0.00005,0,-0.00005,false\n2,-0.5,0,-0.5,false\n3,0.00000000,0,0,false\n"
+        );
+        assert_eq!(
+            output(4, true),
+            "client,available,held,total,locked\n1,0.0000,0,0.0000,false\n2,-0.5,0,-0.5,false\n3,0.0000,0,0,false\n"
+        );
+    }
+
+    #[test]
+    fn test_output_account_json() {
+        let account = Account::new(3, dec!(1.5), dec!(2.0), true);
+
+        assert_eq!(
+            serde_json::to_string(&OutputAccount::new(&account, true)).unwrap(),
+            r#"{"client":3,"availableBalance":"1.5","heldBalance":"2.0","totalBalance":"3.5","locked":true}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&OutputAccount::new(&account, false)).unwrap(),
+            r#"{"client":3,"availableBalance":"1.5","heldBalance":"2.0","totalBalance":"3.5"}"#
+        );
+
+        let (accounts, _) = crate::engine::process_transactions(vec![
+            Transaction::new("deposit".into(), 3, 7, Some(dec!(1.5))),
+            Transaction::new("dispute".into(), 3, 7, None),
+            Transaction::new("chargeback".into(), 3, 7, None),
+        ]);
+        assert_eq!(
+            serde_json::to_string(&OutputAccount::new(&accounts[0], true)).unwrap(),
+            r#"{"client":3,"availableBalance":"0.0","heldBalance":"0.0","totalBalance":"0.0","locked":true,"lockReason":"chargeback of tx 7"}"#
+        );
+        let mut out = Vec::new();
+        process_output(&mut out, accounts, vec![], &OutputConfig::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n3,0.0,0.0,0.0,true\n"
+        );
+    }
+
+    #[test]
+    fn test_held_transactions_json() {
+        let mut engine = crate::engine::Engine::new(crate::engine::EngineConfig::default());
+        for transaction in [
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(5.0))),
+            Transaction::new("deposit".into(), 1, 2, Some(dec!(2.5))),
+            Transaction::new("deposit".into(), 1, 3, Some(dec!(1.0))),
+            Transaction::new("deposit".into(), 2, 4, Some(dec!(4.0))),
+            Transaction::new("dispute".into(), 1, 2, None),
+            Transaction::new("dispute".into(), 1, 1, None),
+            Transaction::new("dispute".into(), 2, 4, None),
+        ] {
+            engine.apply(transaction).unwrap();
+        }
+
+        let account = engine.account(1).unwrap();
+        let json = OutputAccount::new(account, true)
+            .with_held_transactions(engine.held_breakdown(account));
+        assert_eq!(
+            serde_json::to_string(&json).unwrap(),
+            r#"{"client":1,"availableBalance":"1.0","heldBalance":"7.5","totalBalance":"8.5","locked":false,"heldTransactions":[{"tx":1,"amount":"5"},{"tx":2,"amount":"2.5"}]}"#
+        );
+
+        engine
+            .apply(Transaction::new("resolve".into(), 1, 1, None))
+            .unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(engine.held_breakdown(account), vec![(2, dec!(2.5))]);
+    }
+
+    /// Fails the first `failures` writes with a timeout, then writes to `out`.
+    struct FlakyWriter {
+        failures: usize,
+        out: Vec<u8>,
+    }
+
+    impl Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "sink timed out"));
+            }
+            self.out.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_retry_writer() {
+        let accounts = vec![Account::new(1, dec!(1.5), dec!(0), false)];
+        let config = OutputConfig::default();
+        let flaky = || FlakyWriter {
+            failures: 1,
+            out: Vec::new(),
+        };
+
+        let mut writer = RetryWriter::new(flaky(), 2);
+        process_output(&mut writer, accounts.clone(), vec![], &config).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.inner.out).unwrap(),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+        );
+
+        // Fail-fast without retries, and on errors that aren't transient.
+        let err = process_output(
+            &mut RetryWriter::new(flaky(), 0),
+            accounts.clone(),
+            vec![],
+            &config,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = process_output(
+            &mut RetryWriter::new(FailingWriter { writes: 0 }, 2),
+            accounts,
+            vec![],
+            &config,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+    }
+
+    /// Accepts `writes` writes, then fails every further one.
+    struct FailingWriter {
+        writes: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writes == 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            self.writes -= 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_write_error() {
+        let accounts = vec![
+            Account::new(1, dec!(1.0), dec!(0), false),
+            Account::new(2, dec!(2.0), dec!(0), false),
+        ];
+        let config = OutputConfig::default();
+
+        for writes in 0..3 {
+            let err = process_output(
+                &mut FailingWriter { writes },
+                accounts.clone(),
+                vec![],
+                &config,
+            )
+            .unwrap_err();
+            assert_eq!(err.to_string(), "disk full");
+        }
+        assert!(process_output(
+            &mut FailingWriter { writes: 100 },
+            accounts,
+            vec![],
+            &config
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut input = Vec::new();
+        crate::generator::generate(&mut input, 7, 2500).unwrap();
+        let mut stderr = Vec::new();
+        let mut progress = Progress::new(&mut stderr, 1000, Duration::ZERO);
+        for _ in String::from_utf8(input).unwrap().lines().skip(1) {
+            progress.tick().unwrap();
+        }
+        progress.finish().unwrap();
+
+        let stderr = String::from_utf8(stderr).unwrap();
+        let lines: Vec<&str> = stderr.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("progress: 1000 rows ("));
+        assert!(lines[1].starts_with("progress: 2000 rows ("));
+        assert!(lines[2].starts_with("progress: 2500 rows done in "));
+        assert!(lines.iter().all(|line| line.ends_with(" rows/s)")));
+
+        // Throttled by time, a fast run only prints the summary.
+        let mut stderr = Vec::new();
+        let mut progress = Progress::new(&mut stderr, 1, Duration::from_secs(3600));
+        for _ in 0..10 {
+            progress.tick().unwrap();
+        }
+        progress.finish().unwrap();
+        assert_eq!(String::from_utf8(stderr).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+not-a-row
+dispute,2,2,
+chargeback,2,2,
+withdrawal,1,3,50.0
+";
+        let (transactions, parse_errors) =
+            process_reader(input.as_bytes(), &ParseConfig::default()).unwrap();
+        let processed = transactions.len();
+        let (accounts, tx_errors) = crate::engine::process_transactions(transactions);
+
+        let report = Report::new(processed, parse_errors.len() + tx_errors.len(), &accounts);
+
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"processed":5,"errors":2,"accounts":2,"locked":1}"#
+        );
+    }
+
+    #[test]
+    fn test_totals_row() {
+        let accounts = vec![
+            Account::new(1, dec!(1.5), dec!(2.0), false),
+            Account::new(2, dec!(3.25), dec!(0), true),
+        ];
+        let config = OutputConfig {
+            totals_row: true,
+            ..OutputConfig::default()
+        };
+        let mut out = Vec::new();
+
+        process_output(&mut out, accounts, vec![], &config).unwrap();
+
+        let output = String::from_utf8(out).unwrap();
+        assert_eq!(output.lines().last().unwrap(), "# totals,4.75,2.0,6.75");
+        assert!(output
+            .lines()
+            .skip(1)
+            .filter(|line| !line.starts_with('#'))
+            .all(|line| line.split(',').next().unwrap().parse::<u16>().is_ok()));
+    }
+
+    #[test]
+    fn test_omit_zero_accounts() {
+        let (mut accounts, _) = crate::engine::process_transactions(vec![
+            Transaction::new("deposit".into(), 1, 1, Some(dec!(5.0))),
+            Transaction::new("withdrawal".into(), 1, 2, Some(dec!(5.0))),
+            Transaction::new("deposit".into(), 2, 3, Some(dec!(1.0))),
+        ]);
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(accounts.len(), 2);
+
+        // A dispute of spent funds leaves a zero total with funds held.
+        accounts.push(Account::new(3, dec!(-2.0), dec!(2.0), false));
+        accounts.push(Account::new(4, dec!(0), dec!(0), true));
+
+        let accounts = omit_zero_accounts(accounts);
+
+        assert_eq!(
+            accounts,
+            vec![
+                Account::new(2, dec!(1.0), dec!(0), false),
+                Account::new(3, dec!(-2.0), dec!(2.0), false),
+                Account::new(4, dec!(0), dec!(0), true),
+            ]
+        );
     }
 }
@@ -0,0 +1,10 @@
+pub mod cli;
+pub mod diff;
+pub mod engine;
+pub mod generator;
+pub mod helpers;
+#[cfg(all(feature = "server", not(target_arch = "wasm32")))]
+pub mod server;
+pub mod types;
+#[cfg(feature = "wasm")]
+pub mod wasm;
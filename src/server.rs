@@ -0,0 +1,182 @@
+use crate::engine::Engine;
+use crate::helpers::{self, OutputConfig, ParseConfig};
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// Binds `addr`, a TCP `host:port` or (on Unix) a `unix:<path>` socket, and
+/// serves connections with `serve` until accepting fails.
+pub async fn listen(
+    addr: &str,
+    engine: Rc<RefCell<Engine>>,
+    parse: ParseConfig,
+    output: OutputConfig,
+) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix("unix:") {
+        let listener = UnixListener::bind(path)?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            spawn_handler(stream, &engine, &parse, &output);
+        }
+    }
+    serve(TcpListener::bind(addr).await?, engine, parse, output).await
+}
+
+/// Accepts TCP connections, each sending CSV rows (with a header) that are
+/// parsed with `parse` and applied to the shared `engine`. Once a client closes its sending half it
+/// is answered with all accounts as CSV, including those of other
+/// connections.
+///
+/// Connections are handled concurrently on the current thread, so this must
+/// run on a `tokio::task::LocalSet`: the engine isn't `Send`, as its
+/// `before_apply` hook needn't be.
+pub async fn serve(
+    listener: TcpListener,
+    engine: Rc<RefCell<Engine>>,
+    parse: ParseConfig,
+    output: OutputConfig,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_handler(stream, &engine, &parse, &output);
+    }
+}
+
+fn spawn_handler<S>(
+    stream: S,
+    engine: &Rc<RefCell<Engine>>,
+    parse: &ParseConfig,
+    output: &OutputConfig,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let engine = Rc::clone(engine);
+    let parse = parse.clone();
+    let output = output.clone();
+    tokio::task::spawn_local(async move {
+        if let Err(err) = handle(stream, &engine, &parse, &output).await {
+            eprintln!("error serving connection: {}", err);
+        }
+    });
+}
+
+/// Reads one connection to the end, applies its transactions and writes back
+/// the accounts snapshot. A malformed input is answered with the error
+/// instead.
+pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    engine: &RefCell<Engine>,
+    parse: &ParseConfig,
+    output: &OutputConfig,
+) -> io::Result<()> {
+    let mut input = Vec::new();
+    stream.read_to_end(&mut input).await?;
+    let response = match helpers::process_csv_bytes(&input, parse) {
+        Ok((transactions, parse_errors)) => {
+            let mut engine = engine.borrow_mut();
+            let mut errors = parse_errors;
+            errors.extend(engine.apply_all(transactions.into_iter().map(Ok::<_, String>)));
+            let accounts = engine.accounts();
+            let output = OutputConfig {
+                currency: accounts.iter().any(|account| account.currency.is_some()),
+                ..output.clone()
+            };
+            let mut response = Vec::new();
+            helpers::process_output(&mut response, accounts, errors, &output)?;
+            response
+        }
+        Err(err) => format!("error parsing csv: {}\n", err).into_bytes(),
+    };
+    stream.write_all(&response).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use tokio::net::TcpStream;
+    use tokio::task::LocalSet;
+
+    async fn send(addr: std::net::SocketAddr, input: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(input.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = Rc::new(RefCell::new(Engine::new(EngineConfig::default())));
+        let local = LocalSet::new();
+        local.spawn_local(serve(
+            listener,
+            engine,
+            ParseConfig::default(),
+            OutputConfig::default(),
+        ));
+        local
+            .run_until(async move {
+                let response = send(
+            addr,
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,4.0\n",
+        )
+        .await;
+                assert_eq!(
+                    response,
+                    "client,available,held,total,locked\n1,6,0,6,false\n2,5,0,5,false\n"
+                );
+
+                // The engine is shared, so a later connection can dispute earlier rows.
+                let response = send(addr, "type,client,tx,amount\ndispute,2,2,\n").await;
+                assert_eq!(
+                    response,
+                    "client,available,held,total,locked\n1,6,0,6,false\n2,0,5,5,false\n"
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_cli_settings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = Rc::new(RefCell::new(Engine::new(EngineConfig {
+            precision: 8,
+            ..EngineConfig::default()
+        })));
+        let parse = ParseConfig {
+            strict: true,
+            max_scale: 8,
+            ..ParseConfig::default()
+        };
+        let output = OutputConfig {
+            precision: 8,
+            ..OutputConfig::default()
+        };
+        let local = LocalSet::new();
+        local.spawn_local(serve(listener, engine, parse, output));
+        local
+            .run_until(async move {
+                let response = send(addr, "type,client,tx,amount\ndeposit,1,1,0.12345678\n").await;
+                assert_eq!(
+                    response,
+                    "client,available,held,total,locked\n1,0.12345678,0,0.12345678,false\n"
+                );
+
+                // Strict parsing answers a malformed row with the error.
+                let response = send(addr, "type,client,tx,amount\ndeposit,x,2,1.0\n").await;
+                assert!(response.starts_with("error parsing csv: "), "{}", response);
+            })
+            .await;
+    }
+}
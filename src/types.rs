@@ -1,97 +1,638 @@
+use rust_decimal::prelude::ToPrimitive;
 use rust_decimal::Decimal;
+use serde::de::{Error, Visitor};
 use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops;
+use std::str::FromStr;
 
-#[derive(Debug, Serialize, PartialEq, Clone)]
-pub struct Account {
+/// Default maximum number of decimal places accepted for a transaction
+/// amount, see `ParseConfig::max_scale`.
+pub const MAX_AMOUNT_SCALE: u32 = 4;
+
+/// How a dispute is handled when its amount exceeds the available funds, e.g.
+/// a deposit that was partially withdrawn before being disputed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisputePolicy {
+    /// Reject the dispute, so `available` never goes negative and the
+    /// balances stay as they were.
+    StrictAvailable,
+    /// Hold the full amount anyway, leaving `available` negative by the part
+    /// that was already spent; `total` is unchanged.
+    AllowNegative,
+}
+
+/// Money type of account balances and the engine's bookkeeping: `Decimal`
+/// by default, or `i64` for integer minor units, e.g. cents under precision
+/// 2. Transactions are parsed as `Decimal` and converted when applied, so
+/// only the arithmetic the balance invariants need is required besides the
+/// conversion.
+pub trait Amount:
+    Copy
+    + Ord
+    + Default
+    + fmt::Debug
+    + fmt::Display
+    + ops::Add<Output = Self>
+    + ops::Sub<Output = Self>
+    + ops::AddAssign
+    + ops::SubAssign
+{
+    fn zero() -> Self;
+
+    /// A transaction amount or configured limit rounded to `precision`
+    /// decimal places, or `None` if it doesn't fit.
+    fn from_decimal(amount: Decimal, precision: u32) -> Option<Self>;
+
+    /// Rounds a balance to at most `precision` decimal places.
+    fn round(self, precision: u32) -> Self;
+}
+
+impl Amount for Decimal {
+    fn zero() -> Self {
+        Decimal::ZERO
+    }
+
+    /// Trailing zeros are dropped too, so `100` and `100.0000` are applied
+    /// alike.
+    fn from_decimal(amount: Decimal, precision: u32) -> Option<Self> {
+        Some(round_amount(amount, precision).normalize())
+    }
+
+    fn round(self, precision: u32) -> Self {
+        round_amount(self, precision)
+    }
+}
+
+/// Minor units at the configured precision: `1.25` at precision 2 is `125`.
+/// Balances must stay within `i64`, about 9.2e14 units at precision 4.
+impl Amount for i64 {
+    fn zero() -> Self {
+        0
+    }
+
+    fn from_decimal(amount: Decimal, precision: u32) -> Option<Self> {
+        let scale = Decimal::from(10_i64.checked_pow(precision)?);
+        round_amount(amount, precision).checked_mul(scale)?.to_i64()
+    }
+
+    /// Minor units have no places beyond the precision.
+    fn round(self, _precision: u32) -> Self {
+        self
+    }
+}
+
+/// A client's balances.
+///
+/// The balance fields share the `Amount` type, so the methods below are the
+/// only place they should be mutated. They keep these invariants:
+/// - `total == available + held`
+/// - `held` never goes negative, as only disputed amounts are released
+/// - `available` only shrinks through withdrawals, disputes and transfers, each
+///   of which is rejected when it exceeds the available funds (plus any
+///   overdraft for withdrawals and transfers), unless disputes run under
+///   `DisputePolicy::AllowNegative`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Account<A: Amount = Decimal> {
     pub client: u16,
-    pub available: Decimal,
-    pub held: Decimal,
-    pub total: Decimal,
+    pub available: A,
+    pub held: A,
+    pub total: A,
     pub locked: bool,
+    /// Id of the first transaction that touched the account.
+    #[serde(default)]
+    pub first_tx: Option<u32>,
+    /// Number of disputes that ended in a resolve.
+    #[serde(default)]
+    pub resolved_count: u32,
+    /// Number of disputes that ended in a chargeback.
+    #[serde(default)]
+    pub chargeback_count: u32,
+    /// Sum of all deposits ever credited, including incoming transfers.
+    #[serde(default)]
+    pub lifetime_deposited: A,
+    /// Sum of all withdrawals ever debited, including outgoing transfers.
+    #[serde(default)]
+    pub lifetime_withdrawn: A,
+    /// 1-based index of the last transaction in the input that changed the
+    /// account.
+    #[serde(default)]
+    pub last_modified: Option<u64>,
+    /// Why the account is locked, e.g. `chargeback of tx 5`; unset while it
+    /// isn't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<String>,
+    /// Currency of the balances, unset for single-currency input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
 }
 
-impl Account {
-    pub fn new(client: u16, available: Decimal, held: Decimal, locked: bool) -> Self {
+/// Accounts compare equal on their client, currency and balances; audit
+/// metadata such as
+/// `first_tx`, the dispute counters, the lifetime sums, `last_modified` or
+/// the lock reason is ignored.
+impl<A: Amount> PartialEq for Account<A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.client == other.client
+            && self.available == other.available
+            && self.held == other.held
+            && self.total == other.total
+            && self.locked == other.locked
+            && self.currency == other.currency
+    }
+}
+
+/// An unlocked account of client 0 with zero balances.
+impl<A: Amount> Default for Account<A> {
+    fn default() -> Self {
+        Self::empty(0)
+    }
+}
+
+/// Builds an account from `(client, available, held, locked)`, like `new`.
+impl<A: Amount> From<(u16, A, A, bool)> for Account<A> {
+    fn from((client, available, held, locked): (u16, A, A, bool)) -> Self {
+        Self::new(client, available, held, locked)
+    }
+}
+
+impl<A: Amount> Account<A> {
+    pub fn new(client: u16, available: A, held: A, locked: bool) -> Self {
         Self {
             client,
             available,
             held,
             total: available + held,
             locked,
+            first_tx: None,
+            resolved_count: 0,
+            chargeback_count: 0,
+            lifetime_deposited: A::zero(),
+            lifetime_withdrawn: A::zero(),
+            last_modified: None,
+            lock_reason: None,
+            currency: None,
         }
     }
 
     pub fn empty(client: u16) -> Self {
-        Self::new(client, Decimal::from(0), Decimal::from(0), false)
+        Self::new(client, A::zero(), A::zero(), false)
+    }
+
+    /// Whether the balance invariants documented on `Account` hold. Negative
+    /// available funds are allowed, as `DisputePolicy::AllowNegative` can
+    /// produce them.
+    pub fn is_consistent(&self) -> bool {
+        self.total == self.available + self.held && self.held >= A::zero()
+    }
+
+    /// Combines the balances of the same client from another shard: amounts
+    /// and dispute counts are summed and the account is locked if either is.
+    pub fn merge(&mut self, other: &Account<A>) {
+        self.available += other.available;
+        self.held += other.held;
+        self.total += other.total;
+        self.locked |= other.locked;
+        if self.first_tx.is_none() {
+            self.first_tx = other.first_tx;
+        }
+        self.resolved_count += other.resolved_count;
+        self.chargeback_count += other.chargeback_count;
+        self.lifetime_deposited += other.lifetime_deposited;
+        self.lifetime_withdrawn += other.lifetime_withdrawn;
+        self.last_modified = self.last_modified.max(other.last_modified);
+        if self.lock_reason.is_none() {
+            self.lock_reason = other.lock_reason.clone();
+        }
     }
 
-    pub fn deposit(&mut self, amount: Decimal) -> Result<(), String> {
+    pub fn deposit(&mut self, amount: A) -> Result<(), String> {
         self.available += amount;
         self.total += amount;
+        self.lifetime_deposited += amount;
 
+        debug_assert!(self.is_consistent(), "inconsistent balances: {:?}", self);
         Ok(())
     }
 
-    pub fn withdraw(&mut self, amount: Decimal) -> Result<(), String> {
-        if amount > self.available {
-            return Err(format!("Insufficient available funds"));
+    pub fn withdraw(&mut self, amount: A) -> Result<(), String> {
+        self.withdraw_with_overdraft(amount, A::zero())
+    }
+
+    /// Like `withdraw`, but lets `available` go as far as `overdraft` below
+    /// zero.
+    pub fn withdraw_with_overdraft(&mut self, amount: A, overdraft: A) -> Result<(), String> {
+        if amount > self.available + overdraft {
+            return Err("Insufficient available funds".to_string());
         }
         self.available -= amount;
         self.total -= amount;
+        self.lifetime_withdrawn += amount;
 
+        debug_assert!(self.is_consistent(), "inconsistent balances: {:?}", self);
         Ok(())
     }
 
-    pub fn dispute(&mut self, amount: Decimal) -> Result<(), String> {
-        if amount > self.available {
-            return Err(format!("Insufficient available funds"));
+    /// Moves a disputed amount from available to held funds.
+    ///
+    /// Under `DisputePolicy::StrictAvailable` a dispute is rejected when the
+    /// amount exceeds the available funds, so `available` never goes negative.
+    pub fn dispute(&mut self, amount: A, policy: DisputePolicy) -> Result<(), String> {
+        if policy == DisputePolicy::StrictAvailable && amount > self.available {
+            return Err("Insufficient available funds".to_string());
         }
         self.available -= amount;
         self.held += amount;
 
+        debug_assert!(self.is_consistent(), "inconsistent balances: {:?}", self);
         Ok(())
     }
 
-    pub fn resolve(&mut self, amount: Decimal) -> Result<(), String> {
+    pub fn resolve(&mut self, amount: A) -> Result<(), String> {
         if amount > self.held {
-            return Err(format!("Insufficient held funds"));
+            return Err("Insufficient held funds".to_string());
         }
         self.available += amount;
         self.held -= amount;
+        self.resolved_count += 1;
 
+        debug_assert!(self.is_consistent(), "inconsistent balances: {:?}", self);
         Ok(())
     }
 
-    pub fn chargeback(&mut self, amount: Decimal) -> Result<(), String> {
+    pub fn chargeback(&mut self, amount: A) -> Result<(), String> {
         if amount > self.held {
-            return Err(format!("Insufficient held funds"));
+            return Err("Insufficient held funds".to_string());
         }
         self.held -= amount;
         self.total -= amount;
         self.locked = true;
+        self.chargeback_count += 1;
 
+        debug_assert!(self.is_consistent(), "inconsistent balances: {:?}", self);
         Ok(())
     }
+
+    /// Rounds the balances to at most `scale` decimal places, keeping
+    /// `total == available + held`.
+    pub fn normalize(&mut self, scale: u32) {
+        self.available = self.available.round(scale);
+        self.held = self.held.round(scale);
+        self.total = self.available + self.held;
+    }
+
+    /// Re-credits a charged back amount. Unlocking is left to the caller, as
+    /// other chargebacks may still be in force.
+    pub fn reverse_chargeback(&mut self, amount: A) {
+        self.available += amount;
+        self.total += amount;
+
+        debug_assert!(self.is_consistent(), "inconsistent balances: {:?}", self);
+    }
+}
+
+impl Account {
+    /// Largest number of decimal places among the balances.
+    pub fn max_scale(&self) -> u32 {
+        self.available
+            .scale()
+            .max(self.held.scale())
+            .max(self.total.scale())
+    }
+
+    /// The balances as `f64`, for stats libraries that can't take `Decimal`.
+    /// Each balance is rounded to the nearest `f64` separately, which keeps
+    /// only 15 to 17 significant digits, so e.g. `1234567890.12345678`
+    /// loses its last places and `total` may differ from `available + held`
+    /// in the last bit. Don't feed the result back into the engine.
+    #[cfg(feature = "analytics")]
+    pub fn to_f64_view(&self) -> F64AccountView {
+        F64AccountView {
+            client: self.client,
+            available: self.available.to_f64().unwrap_or(f64::NAN),
+            held: self.held.to_f64().unwrap_or(f64::NAN),
+            total: self.total.to_f64().unwrap_or(f64::NAN),
+            locked: self.locked,
+        }
+    }
+}
+
+/// An account's balances as `f64`, see `Account::to_f64_view`.
+#[cfg(feature = "analytics")]
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct F64AccountView {
+    pub client: u16,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Transaction {
-    #[serde(rename(deserialize = "type"))]
+    #[serde(rename = "type")]
     pub transaction_type: String,
+    #[serde(deserialize_with = "deserialize_client")]
     pub client: u16,
+    #[serde(deserialize_with = "deserialize_tx")]
     pub tx: u32,
-    pub amount: Decimal,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Option<Decimal>,
+    /// Receiving client of a `transfer`; the column is optional in CSV input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<u16>,
+    /// Currency of `amount`; the column is optional in CSV input. Disputes,
+    /// resolves and chargebacks may leave it empty to use the currency of the
+    /// transaction they reference.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    /// Position of the transaction in the canonical order, e.g. when merging
+    /// files or streams; the column is optional in CSV input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
 }
 
-#[cfg(test)]
 impl Transaction {
-    pub fn new(transaction_type: String, client: u16, tx: u32, amount: Decimal) -> Self {
+    pub const COLUMNS: [&'static str; 4] = ["type", "client", "tx", "amount"];
+
+    /// Builds a transaction without going through CSV. `amount` is only
+    /// required for deposits and withdrawals; disputes, resolves and
+    /// chargebacks reference the amount of the transaction they target.
+    ///
+    /// ```
+    /// use rust_decimal_macros::dec;
+    /// use toy_transactions_engine::engine::process_transactions;
+    /// use toy_transactions_engine::types::Transaction;
+    ///
+    /// let (accounts, errors) = process_transactions(vec![
+    ///     Transaction::new("deposit".into(), 1, 1, Some(dec!(10.0))),
+    ///     Transaction::new("dispute".into(), 1, 1, None),
+    /// ]);
+    ///
+    /// assert_eq!(accounts[0].available, dec!(0.0));
+    /// assert_eq!(accounts[0].held, dec!(10.0));
+    /// assert!(errors.is_empty());
+    /// ```
+    pub fn new(transaction_type: String, client: u16, tx: u32, amount: Option<Decimal>) -> Self {
         Self {
             transaction_type,
             client,
             tx,
             amount,
+            destination: None,
+            currency: None,
+            sequence: None,
+        }
+    }
+
+    pub fn with_destination(mut self, destination: u16) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+}
+
+/// Rounds `amount` to at most `precision` decimal places, leaving amounts that
+/// already fit unchanged (including their scale).
+pub fn round_amount(amount: Decimal, precision: u32) -> Decimal {
+    if amount.scale() > precision {
+        amount.round_dp(precision)
+    } else {
+        amount
+    }
+}
+
+fn deserialize_client<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let client = u64::deserialize(deserializer)?;
+    u16::try_from(client).map_err(|_| {
+        D::Error::custom(format!(
+            "client id {} out of range (max {})",
+            client,
+            u16::MAX
+        ))
+    })
+}
+
+fn deserialize_tx<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let tx = u64::deserialize(deserializer)?;
+    u32::try_from(tx)
+        .map_err(|_| D::Error::custom(format!("tx id {} out of range (max {})", tx, u32::MAX)))
+}
+
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(AmountVisitor)
+}
+
+/// Reads an amount like `Decimal` does, but rejects values that aren't
+/// decimal numbers (e.g. `NaN`, `inf` or `1,000`) as `invalid amount '<value>'`
+/// rather than with serde's type error.
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Option<Decimal>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a decimal amount")
+    }
+
+    fn visit_none<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+        Decimal::from_str(value)
+            .or_else(|_| Decimal::from_scientific(value))
+            .map(Some)
+            .map_err(|_| E::custom(format!("invalid amount '{}'", value)))
+    }
+
+    fn visit_f64<E: Error>(self, value: f64) -> Result<Self::Value, E> {
+        if value.is_finite() {
+            self.visit_str(&value.to_string())
+        } else {
+            Err(E::custom(format!("invalid amount '{}'", value)))
         }
     }
+
+    fn visit_i64<E: Error>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(Some(Decimal::from(value)))
+    }
+
+    fn visit_u64<E: Error>(self, value: u64) -> Result<Self::Value, E> {
+        Ok(Some(Decimal::from(value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_default_and_from() {
+        let account = Account::default();
+        assert_eq!(account, Account::new(0, dec!(0), dec!(0), false));
+        assert!(account.is_consistent());
+
+        let account = Account::from((7, dec!(1.5), dec!(2.25), true));
+        assert_eq!(account, Account::new(7, dec!(1.5), dec!(2.25), true));
+        assert_eq!(account.total, account.available + account.held);
+        assert_eq!(account.total, dec!(3.75));
+        assert!(account.is_consistent());
+    }
+
+    #[test]
+    fn test_balance_invariants() {
+        let mut account = Account::empty(1);
+        assert!(account.is_consistent());
+
+        account.deposit(dec!(100.0)).unwrap();
+        account.withdraw(dec!(30.0)).unwrap();
+        account
+            .dispute(dec!(50.0), DisputePolicy::StrictAvailable)
+            .unwrap();
+        assert_eq!(account.available, dec!(20.0));
+        assert_eq!(account.held, dec!(50.0));
+        assert!(account.is_consistent());
+
+        account.resolve(dec!(20.0)).unwrap();
+        account.chargeback(dec!(30.0)).unwrap();
+        assert!(account.is_consistent());
+        assert_eq!(account.total, dec!(40.0));
+    }
+
+    #[test]
+    fn test_lifetime_sums() {
+        let mut account = Account::empty(1);
+
+        account.deposit(dec!(100.0)).unwrap();
+        account.deposit(dec!(50.0)).unwrap();
+        account.withdraw(dec!(30.0)).unwrap();
+        account
+            .dispute(dec!(50.0), DisputePolicy::StrictAvailable)
+            .unwrap();
+        account.chargeback(dec!(50.0)).unwrap();
+        assert!(account.withdraw(dec!(1000.0)).is_err());
+
+        assert_eq!(account.lifetime_deposited, dec!(150.0));
+        assert_eq!(account.lifetime_withdrawn, dec!(30.0));
+        assert_eq!(account.total, dec!(70.0));
+    }
+
+    #[test]
+    fn test_rejected_operations_keep_balances() {
+        let mut account = Account::new(1, dec!(10.0), dec!(5.0), false);
+
+        assert!(account.withdraw(dec!(10.1)).is_err());
+        assert!(account
+            .dispute(dec!(10.1), DisputePolicy::StrictAvailable)
+            .is_err());
+        assert!(account.resolve(dec!(5.1)).is_err());
+        assert!(account.chargeback(dec!(5.1)).is_err());
+
+        assert_eq!(account, Account::new(1, dec!(10.0), dec!(5.0), false));
+        assert!(account.is_consistent());
+    }
+
+    #[test]
+    fn test_withdraw_with_overdraft() {
+        let mut account = Account::new(1, dec!(20.0), dec!(0), false);
+
+        account
+            .withdraw_with_overdraft(dec!(50.0), dec!(50))
+            .unwrap();
+        assert_eq!(account, Account::new(1, dec!(-30.0), dec!(0), false));
+        assert!(account.is_consistent());
+
+        assert_eq!(
+            account.withdraw_with_overdraft(dec!(30.0), dec!(50)),
+            Err("Insufficient available funds".to_string())
+        );
+        assert_eq!(account.available, dec!(-30.0));
+    }
+
+    #[test]
+    fn test_dispute_allowing_negative() {
+        let mut account = Account::new(1, dec!(10.0), dec!(0), false);
+
+        account
+            .dispute(dec!(25.0), DisputePolicy::AllowNegative)
+            .unwrap();
+
+        assert_eq!(account, Account::new(1, dec!(-15.0), dec!(25.0), false));
+        assert!(account.is_consistent());
+    }
+
+    #[test]
+    fn test_minor_units_amount() {
+        let mut account: Account<i64> = Account::empty(1);
+
+        account.deposit(10_000).unwrap();
+        account.withdraw(2_550).unwrap();
+        account
+            .dispute(5_000, DisputePolicy::StrictAvailable)
+            .unwrap();
+        assert!(account.withdraw(2_451).is_err());
+        account.chargeback(5_000).unwrap();
+
+        assert_eq!(account, Account::new(1, 2_450, 0, true));
+        assert_eq!(account.lifetime_deposited, 10_000);
+        assert!(account.is_consistent());
+
+        let mut merged = Account::from((1, 50, 25, false));
+        merged.merge(&account);
+        assert_eq!(merged, Account::new(1, 2_500, 25, true));
+    }
+
+    #[test]
+    fn test_inconsistent_balances_are_detected() {
+        let mut account = Account::new(1, dec!(10.0), dec!(5.0), false);
+        account.held = dec!(-1.0);
+        account.total = account.available + account.held;
+        assert!(!account.is_consistent());
+
+        let mut account = Account::new(1, dec!(10.0), dec!(5.0), false);
+        account.total = dec!(10.0);
+        assert!(!account.is_consistent());
+    }
+
+    #[cfg(feature = "analytics")]
+    #[test]
+    fn test_f64_view() {
+        let view = Account::new(3, dec!(1.5), dec!(0.25), true).to_f64_view();
+        assert_eq!(
+            view,
+            F64AccountView {
+                client: 3,
+                available: 1.5,
+                held: 0.25,
+                total: 1.75,
+                locked: true,
+            }
+        );
+
+        // Beyond f64's significant digits, the last places are rounded away.
+        let view = Account::new(1, dec!(1234567890.12345678), dec!(0), false).to_f64_view();
+        assert_eq!(view.available, 1234567890.1234567);
+        assert_eq!(view.available.to_string(), "1234567890.1234567");
+    }
 }
@@ -0,0 +1,8 @@
+use wasm_bindgen::prelude::*;
+
+/// Processes CSV transactions and returns the accounts as CSV, see
+/// `helpers::process_csv_string`.
+#[wasm_bindgen(js_name = processCsv)]
+pub fn process_csv(input: &str) -> Result<String, JsValue> {
+    crate::helpers::process_csv_string(input).map_err(|err| JsValue::from_str(&err.to_string()))
+}
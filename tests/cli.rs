@@ -0,0 +1,172 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+fn write_input(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "toy-transactions-engine-{}-{}",
+        std::process::id(),
+        name
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+fn run(args: &[&str]) -> (Option<i32>, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_toy-transactions-engine"))
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.code(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn test_quiet() {
+    let input = write_input(
+        "quiet.csv",
+        "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,20.0\ndeposit,x,3,1.0\n",
+    );
+    let input = input.to_str().unwrap();
+
+    let (code, stdout, stderr) = run(&[input, "true", "--quiet", "--report", "json"]);
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+    assert_eq!(stderr, "");
+
+    // Without --quiet the same run has diagnostics on both streams.
+    let (_, stdout, stderr) = run(&[input, "true", "--report", "json"]);
+    assert!(stdout.starts_with("Skipping malformed row at line 4"));
+    assert!(stderr.starts_with("{\"processed\":2"));
+
+    let (code, stdout, stderr) = run(&["missing.txt", "--quiet"]);
+    assert_eq!(code, Some(1));
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn test_empty_input() {
+    let inputs = vec![
+        ("empty.csv", ""),
+        ("header-only.csv", "type,client,tx,amount\n"),
+    ];
+    for (name, contents) in inputs {
+        let input = write_input(name, contents);
+        let input = input.to_str().unwrap();
+
+        let (code, stdout, stderr) = run(&[input]);
+        assert_eq!(code, Some(0));
+        assert_eq!(stdout, "client,available,held,total,locked\n");
+        assert_eq!(
+            stderr,
+            format!("input \"{}\" contains no transactions\n", input)
+        );
+
+        let (code, stdout, stderr) = run(&[input, "--quiet"]);
+        assert_eq!(code, Some(0));
+        assert_eq!(stdout, "client,available,held,total,locked\n");
+        assert_eq!(stderr, "");
+    }
+}
+
+#[test]
+fn test_report_json() {
+    // Two applied deposits, a rejected withdrawal, a dispute charged back and
+    // a malformed row that is skipped.
+    let input = write_input(
+        "report.csv",
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,50.0\ndispute,2,2,\nchargeback,2,2,\ndeposit,x,6,1.0\n",
+    );
+
+    let (code, stdout, stderr) = run(&[input.to_str().unwrap(), "--report", "json"]);
+
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,10,0,10,false\n2,0,0,0,true\n"
+    );
+    let report: serde_json::Value = serde_json::from_str(stderr.trim_end()).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!({"processed": 5, "errors": 2, "accounts": 2, "locked": 1})
+    );
+}
+
+#[test]
+fn test_progress_counts_rows_read() {
+    // The malformed row is skipped, but still read.
+    let input = write_input(
+        "progress.csv",
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,x,2,1.0\nwithdrawal,1,3,5.0\n",
+    );
+
+    let (code, _, stderr) = run(&[input.to_str().unwrap(), "--progress"]);
+
+    assert_eq!(code, Some(0));
+    assert!(
+        stderr.starts_with("progress: 3 rows done in "),
+        "{:?}",
+        stderr
+    );
+}
+
+#[test]
+fn test_fail_on_locked() {
+    let input = write_input(
+        "fail-on-locked.csv",
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndispute,2,2,\nchargeback,2,2,\ndeposit,3,3,1.0\ndispute,3,3,\nchargeback,3,3,\n",
+    );
+    let input = input.to_str().unwrap();
+
+    let (code, stdout, stderr) = run(&[input]);
+    assert_eq!(code, Some(0));
+    assert_eq!(stderr, "");
+
+    let (code, stdout_failing, stderr) = run(&[input, "--fail-on-locked"]);
+    assert_eq!(code, Some(1));
+    assert_eq!(stdout_failing, stdout);
+    assert_eq!(stderr, "locked accounts: 2, 3\n");
+}
+
+#[test]
+fn test_negative_total_warning() {
+    let input = write_input(
+        "negative-total.csv",
+        "type,client,tx,amount\ndeposit,1,1,100\nwithdrawal,1,2,80\ndispute,1,1,\nchargeback,1,1,\n",
+    );
+    let input = input.to_str().unwrap();
+    let warning = "Warning: total of client 1 went negative (-80) after transaction \"1\"\n";
+
+    // A warning goes to stderr and, rejecting nothing, isn't counted as an
+    // error.
+    let (code, stdout, stderr) = run(&[
+        input,
+        "true",
+        "--dispute-policy",
+        "allow-negative",
+        "--report",
+        "json",
+    ]);
+    assert_eq!(code, Some(0));
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,-80,0,-80,true\n"
+    );
+    assert_eq!(
+        stderr,
+        format!(
+            "{}{{\"processed\":4,\"errors\":0,\"accounts\":1,\"locked\":1}}\n",
+            warning
+        )
+    );
+
+    let (code, stdout, _) = run(&[input, "--dispute-policy", "allow-negative", "--validate"]);
+    assert_eq!(code, Some(0));
+    assert_eq!(stdout, "0 errors\n");
+}